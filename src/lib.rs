@@ -1,14 +1,40 @@
 use rusqlite::params;
 use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, ValueRef};
-use rusqlite::Connection;
+use rusqlite::{Connection, TransactionBehavior};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::fmt::{self, Display};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-const DB_VERSION: u32 = 2;
+const DB_VERSION: u32 = 14;
+
+/// How long to wait for a lock held by another process before giving up.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(60);
+/// How often a blocking take checks whether the database has been modified.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// How often a blocking take retries even if the database hasn't been modified, since jobs can
+/// become available just by the passage of time (e.g. when a retry's backoff elapses).
+const RECHECK_INTERVAL: Duration = Duration::from_secs(1);
 
 pub type JobId = u32;
 pub type TaskId = u32;
 
+// Result codes logged for jobs that didn't exit normally. Exit codes are logged as-is, and a
+// command killed by a signal is logged as 256 + SIGNAL, so these start above that range.
+
+/// The command could not be started.
+pub const RESULT_FAILED_TO_START: i32 = 512;
+/// The job's lease expired before it finished.
+pub const RESULT_LEASE_EXPIRED: i32 = 513;
+/// The process running the job exited without logging its result.
+pub const RESULT_ABANDONED: i32 = 514;
+/// The command ran past its timeout, and was killed.
+pub const RESULT_TIMED_OUT: i32 = 515;
+/// The job was cancelled with [`Db::cancel`]. Cancelled jobs aren't retried.
+pub const RESULT_CANCELLED: i32 = 516;
+/// The job was preempted by more urgent work (see [`Db::preempt`]), and its repetition requeued.
+pub const RESULT_PREEMPTED: i32 = 517;
+
 #[derive(Debug)]
 #[non_exhaustive]
 pub enum Error {
@@ -34,15 +60,60 @@ pub struct Job {
     pub data: Vec<u8>,
 }
 
+/// How a task's failed repetitions are retried.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct RetryPolicy {
+    /// Total number of attempts per repetition, including the first.
+    pub max_attempts: u32,
+    /// Time to wait before the first retry.
+    pub delay: Duration,
+    /// Factor by which the delay grows for each subsequent retry.
+    pub backoff: f64,
+}
+
+/// Limits on the commands run for a task's repetitions. `None` means no limit.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct Limits {
+    /// Wall-clock time after which the command is terminated.
+    pub timeout: Option<Duration>,
+    /// Maximum size of the command's virtual memory, in bytes.
+    pub max_memory: Option<u64>,
+    /// Maximum CPU time the command can use.
+    pub max_cpu_time: Option<Duration>,
+    /// Niceness to run the command with.
+    pub nice: Option<i32>,
+}
+
+impl Limits {
+    /// Each limit that is set here, otherwise the corresponding one from `defaults`.
+    pub fn or(self, defaults: Limits) -> Limits {
+        Limits {
+            timeout: self.timeout.or(defaults.timeout),
+            max_memory: self.max_memory.or(defaults.max_memory),
+            max_cpu_time: self.max_cpu_time.or(defaults.max_cpu_time),
+            nice: self.nice.or(defaults.nice),
+        }
+    }
+}
+
 pub struct Db {
     conn: Connection,
 }
 
 fn prepare_conn(conn: &Connection) -> Result<()> {
     conn.execute("PRAGMA foreign_keys = 1", [])?;
+    conn.busy_timeout(BUSY_TIMEOUT)?;
     Ok(())
 }
 
+/// Current time, as fractional seconds since the unix epoch.
+fn now() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system clock is set before 1970")
+        .as_secs_f64()
+}
+
 fn get_version(conn: &Connection) -> Result<u32> {
     let mut version = conn.prepare("SELECT version FROM meta")?;
     let mut version = version.query([])?;
@@ -83,7 +154,178 @@ fn upgrade_v1(conn: &Connection) -> Result<()> {
         [],
     )?;
     conn.execute("CREATE TABLE job_finish (job PRIMARY KEY REFERENCES job, result INTEGER, time INTEGER, data BLOB)", [])?;
-    conn.execute("UPDATE meta SET version = ?", [DB_VERSION])?;
+    conn.execute("UPDATE meta SET version = ?", [2])?;
+
+    post_upgrade(conn)
+}
+
+fn upgrade_v2(conn: &Connection) -> Result<()> {
+    pre_upgrade(conn, 2, 3)?;
+
+    conn.execute(
+        "ALTER TABLE job ADD requeued INTEGER NOT NULL DEFAULT 0",
+        [],
+    )?;
+    conn.execute("UPDATE meta SET version = ?", [3])?;
+
+    post_upgrade(conn)
+}
+
+fn upgrade_v3(conn: &Connection) -> Result<()> {
+    pre_upgrade(conn, 3, 4)?;
+
+    conn.execute("ALTER TABLE task ADD max_attempts INTEGER", [])?;
+    conn.execute("ALTER TABLE task ADD retry_delay REAL", [])?;
+    conn.execute("ALTER TABLE task ADD retry_backoff REAL", [])?;
+    conn.execute("ALTER TABLE job ADD attempt INTEGER NOT NULL DEFAULT 1", [])?;
+    conn.execute("ALTER TABLE job ADD retry REFERENCES job", [])?;
+    conn.execute("ALTER TABLE job ADD retry_at REAL", [])?;
+    conn.execute("ALTER TABLE job ADD failed INTEGER NOT NULL DEFAULT 0", [])?;
+    conn.execute("UPDATE meta SET version = ?", [4])?;
+
+    post_upgrade(conn)
+}
+
+fn upgrade_v4(conn: &Connection) -> Result<()> {
+    pre_upgrade(conn, 4, 5)?;
+
+    conn.execute("ALTER TABLE job ADD lease REAL", [])?;
+    conn.execute("ALTER TABLE job ADD lease_expires REAL", [])?;
+    conn.execute("UPDATE meta SET version = ?", [5])?;
+
+    post_upgrade(conn)
+}
+
+fn upgrade_v5(conn: &Connection) -> Result<()> {
+    pre_upgrade(conn, 5, 6)?;
+
+    conn.execute("ALTER TABLE job ADD pid INTEGER", [])?;
+    conn.execute("ALTER TABLE job ADD host TEXT", [])?;
+    conn.execute("ALTER TABLE job ADD pid_start INTEGER", [])?;
+    conn.execute("UPDATE meta SET version = ?", [6])?;
+
+    post_upgrade(conn)
+}
+
+fn upgrade_v6(conn: &Connection) -> Result<()> {
+    pre_upgrade(conn, 6, 7)?;
+
+    // Times are now fractional unix timestamps. They used to be logged as date strings, without
+    // the time of day; those are no use for timing anything, so they're dropped.
+    for table in &["job_start", "job_finish"] {
+        conn.execute(
+            &format!(
+                "UPDATE {} SET time = CASE WHEN time LIKE '%:%' \
+                   THEN CAST(strftime('%s', time) AS REAL) END \
+                 WHERE typeof(time) = 'text'",
+                table
+            ),
+            [],
+        )?;
+    }
+    conn.execute("ALTER TABLE task ADD time REAL", [])?;
+    // Databases upgraded from version 1 have no job time.
+    let has_job_time: bool = conn.query_row(
+        "SELECT count(1) FROM pragma_table_info('job') WHERE name = 'time'",
+        [],
+        |row| row.get(0),
+    )?;
+    if !has_job_time {
+        conn.execute("ALTER TABLE job ADD time REAL", [])?;
+    }
+    conn.execute("UPDATE meta SET version = ?", [7])?;
+
+    post_upgrade(conn)
+}
+
+fn upgrade_v7(conn: &Connection) -> Result<()> {
+    pre_upgrade(conn, 7, 8)?;
+
+    conn.execute(
+        "CREATE TABLE task_dep (task REFERENCES task, after REFERENCES task, PRIMARY KEY (task, after))",
+        [],
+    )?;
+    conn.execute("UPDATE meta SET version = ?", [8])?;
+
+    post_upgrade(conn)
+}
+
+fn upgrade_v8(conn: &Connection) -> Result<()> {
+    pre_upgrade(conn, 8, 9)?;
+
+    // Dependencies used to be on success only.
+    conn.execute(
+        "ALTER TABLE task_dep ADD condition TEXT NOT NULL DEFAULT 'afterok'",
+        [],
+    )?;
+    conn.execute("UPDATE meta SET version = ?", [9])?;
+
+    post_upgrade(conn)
+}
+
+fn upgrade_v9(conn: &Connection) -> Result<()> {
+    pre_upgrade(conn, 9, 10)?;
+
+    conn.execute("ALTER TABLE task ADD timeout REAL", [])?;
+    conn.execute("ALTER TABLE task ADD max_memory INTEGER", [])?;
+    conn.execute("ALTER TABLE task ADD max_cpu_time REAL", [])?;
+    conn.execute("ALTER TABLE task ADD nice INTEGER", [])?;
+    conn.execute("UPDATE meta SET version = ?", [10])?;
+
+    post_upgrade(conn)
+}
+
+fn upgrade_v10(conn: &Connection) -> Result<()> {
+    pre_upgrade(conn, 10, 11)?;
+
+    for column in &[
+        "user_time REAL",
+        "system_time REAL",
+        "max_rss INTEGER",
+        "in_blocks INTEGER",
+        "out_blocks INTEGER",
+    ] {
+        conn.execute(&format!("ALTER TABLE job_finish ADD {}", column), [])?;
+    }
+    conn.execute("UPDATE meta SET version = ?", [11])?;
+
+    post_upgrade(conn)
+}
+
+fn upgrade_v11(conn: &Connection) -> Result<()> {
+    pre_upgrade(conn, 11, 12)?;
+
+    conn.execute("ALTER TABLE job ADD cancel INTEGER", [])?;
+    conn.execute("UPDATE meta SET version = ?", [12])?;
+
+    post_upgrade(conn)
+}
+
+fn upgrade_v12(conn: &Connection) -> Result<()> {
+    pre_upgrade(conn, 12, 13)?;
+
+    conn.execute("ALTER TABLE job ADD suspend INTEGER NOT NULL DEFAULT 0", [])?;
+    conn.execute(
+        "CREATE TABLE job_suspend (job REFERENCES job, start REAL NOT NULL, end REAL)",
+        [],
+    )?;
+    conn.execute("UPDATE meta SET version = ?", [13])?;
+
+    post_upgrade(conn)
+}
+
+fn upgrade_v13(conn: &Connection) -> Result<()> {
+    pre_upgrade(conn, 13, 14)?;
+
+    conn.execute(
+        "ALTER TABLE task ADD preemptible INTEGER NOT NULL DEFAULT 0",
+        [],
+    )?;
+    conn.execute(
+        "ALTER TABLE job ADD preempted INTEGER NOT NULL DEFAULT 0",
+        [],
+    )?;
+    conn.execute("UPDATE meta SET version = ?", [14])?;
 
     post_upgrade(conn)
 }
@@ -94,6 +336,18 @@ fn upgrade(conn: &mut Connection) -> Result<()> {
         let version = get_version(&tx)?;
         match version {
             1 => upgrade_v1(&tx)?,
+            2 => upgrade_v2(&tx)?,
+            3 => upgrade_v3(&tx)?,
+            4 => upgrade_v4(&tx)?,
+            5 => upgrade_v5(&tx)?,
+            6 => upgrade_v6(&tx)?,
+            7 => upgrade_v7(&tx)?,
+            8 => upgrade_v8(&tx)?,
+            9 => upgrade_v9(&tx)?,
+            10 => upgrade_v10(&tx)?,
+            11 => upgrade_v11(&tx)?,
+            12 => upgrade_v12(&tx)?,
+            13 => upgrade_v13(&tx)?,
             DB_VERSION => break Ok(()),
             db_version => break Err(Error::DbTooNew { db_version }.into()),
         }
@@ -101,6 +355,240 @@ fn upgrade(conn: &mut Connection) -> Result<()> {
     }
 }
 
+/// An SQL expression for the time `job.id` has spent suspended, counting any interval still open
+/// as ending at `end`.
+fn paused_time(end: &str) -> String {
+    format!(
+        "(SELECT coalesce(sum(coalesce(job_suspend.end, {}) - job_suspend.start), 0) \
+         FROM job_suspend WHERE job_suspend.job = job.id)",
+        end
+    )
+}
+
+fn get_retry_policy(conn: &Connection, task: TaskId) -> Result<Option<RetryPolicy>> {
+    let policy = conn.query_row(
+        "SELECT max_attempts, retry_delay, retry_backoff FROM task WHERE id = ?",
+        [task],
+        |row| {
+            let max_attempts: Option<u32> = row.get(0)?;
+            let delay: Option<f64> = row.get(1)?;
+            let backoff: Option<f64> = row.get(2)?;
+            Ok(max_attempts.map(|max_attempts| RetryPolicy {
+                max_attempts,
+                delay: Duration::from_secs_f64(delay.unwrap_or(0.0)),
+                backoff: backoff.unwrap_or(1.0),
+            }))
+        },
+    )?;
+    Ok(policy)
+}
+
+/// Apply a failed job's retry policy, if its task has one.
+fn retry_failed(conn: &Connection, job: JobId) -> Result<()> {
+    let (task, attempt): (TaskId, u32) =
+        conn.query_row("SELECT task, attempt FROM job WHERE id = ?", [job], |row| {
+            Ok((row.get(0)?, row.get(1)?))
+        })?;
+    let policy = match get_retry_policy(conn, task)? {
+        Some(policy) => policy,
+        None => return Ok(()),
+    };
+    if attempt < policy.max_attempts {
+        let delay = policy.delay.as_secs_f64() * policy.backoff.powi(attempt as i32 - 1);
+        conn.execute(
+            "UPDATE job SET requeued = 1, retry_at = ? WHERE id = ?",
+            params![now() + delay, job],
+        )?;
+    } else {
+        conn.execute("UPDATE job SET failed = 1 WHERE id = ?", [job])?;
+    }
+    Ok(())
+}
+
+/// Log a job that will never finish normally as finished with the given result, and put its
+/// repetition back in the queue (subject to its task's retry policy).
+fn abandon(conn: &Connection, job: JobId, result: i32) -> Result<()> {
+    conn.execute(
+        "INSERT INTO job_finish (job, result, time) VALUES (?, ?, ?)",
+        params![job, result, now()],
+    )?;
+    match get_retry_policy(conn, job_task(conn, job)?)? {
+        Some(_) => retry_failed(conn, job),
+        None => {
+            conn.execute("UPDATE job SET requeued = 1 WHERE id = ?", [job])?;
+            Ok(())
+        }
+    }
+}
+
+/// Abandon unfinished jobs whose leases have expired.
+fn reclaim_expired(conn: &Connection, now: f64) -> Result<()> {
+    let expired = conn
+        .prepare(
+            "SELECT id FROM job WHERE lease_expires < ? AND NOT requeued \
+             AND NOT EXISTS (SELECT 1 FROM job_finish WHERE job_finish.job = job.id)",
+        )?
+        .query_map([now], |row| row.get(0))?
+        .collect::<rusqlite::Result<Vec<JobId>>>()?;
+    for job in expired {
+        abandon(conn, job, RESULT_LEASE_EXPIRED)?;
+    }
+    Ok(())
+}
+
+/// Whether all of a task's repetitions have finished (and if so, whether they all succeeded).
+fn task_outcome(conn: &Connection, task: TaskId) -> Result<Option<bool>> {
+    // Requeued jobs don't count: their repetitions are either retried or still waiting.
+    let (count, jobs, finished, succeeded): (u64, u64, u64, u64) = conn.query_row(
+        "SELECT task.count, count(job.id), count(job_finish.job), \
+           count(CASE WHEN job_finish.result = 0 THEN 1 END) \
+         FROM task \
+         LEFT JOIN job ON job.task = task.id AND NOT job.requeued \
+         LEFT JOIN job_finish ON job_finish.job = job.id \
+         WHERE task.id = ?",
+        [task],
+        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+    )?;
+    if jobs < count || finished < jobs {
+        return Ok(None);
+    }
+    Ok(Some(succeeded == finished))
+}
+
+fn dependencies(conn: &Connection, task: TaskId) -> Result<Vec<(TaskId, Condition)>> {
+    let deps = conn
+        .prepare("SELECT after, condition FROM task_dep WHERE task = ? ORDER BY after")?
+        .query_map([task], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(deps)
+}
+
+fn blockers(conn: &Connection, task: TaskId) -> Result<Vec<Blocker>> {
+    let mut blockers = Vec::new();
+    for (after, condition) in dependencies(conn, task)? {
+        let reason = match (condition, task_outcome(conn, after)?) {
+            (_, None) => BlockReason::Unfinished,
+            (Condition::Ok, Some(false)) => BlockReason::Failed,
+            (Condition::NotOk, Some(true)) => BlockReason::Succeeded,
+            _ => continue,
+        };
+        blockers.push(Blocker { after, reason });
+    }
+    Ok(blockers)
+}
+
+/// How many of a task's repetitions are waiting to be taken (including any backing off).
+fn queued_repetitions(conn: &Connection, task: TaskId) -> Result<u64> {
+    let (count, taken): (u64, u64) = conn.query_row(
+        "SELECT count, (SELECT count(1) FROM job WHERE task = task.id AND NOT requeued) \
+         FROM task WHERE id = ?",
+        [task],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )?;
+    Ok(count.saturating_sub(taken))
+}
+
+fn job_task(conn: &Connection, job: JobId) -> Result<TaskId> {
+    Ok(conn.query_row("SELECT task FROM job WHERE id = ?", [job], |row| row.get(0))?)
+}
+
+/// Take a job, in a transaction that already holds the write lock. Returns the job and the id of
+/// the new job row.
+fn take_job(
+    conn: &Connection,
+    worker: &str,
+    lease: Option<Duration>,
+) -> Result<Option<(Job, JobId)>> {
+    // A repetition is unavailable if it has a job that wasn't requeued, or if it was requeued
+    // for a retry that is still backing off.
+    const JOB_Q: &str = "SELECT task.id, task.data FROM task \
+       LEFT JOIN (SELECT job.task, count(1) as c FROM job \
+         WHERE NOT job.requeued \
+           OR (job.retry_at > ?1 AND NOT EXISTS (SELECT 1 FROM job AS r WHERE r.retry = job.id)) \
+         GROUP BY job.task) as w
+       ON w.task = task.id \
+     WHERE COALESCE(w.c, 0) < task.count \
+     ORDER BY COALESCE(task.priority, 0), task.id";
+    // If the repetition being taken is a retry, continue its attempt count. Being preempted
+    // doesn't use up an attempt (but a job that was marked for preemption and finished some
+    // other way did).
+    const RETRY_Q: &str = "SELECT id, attempt - EXISTS (SELECT 1 FROM job_finish \
+       WHERE job_finish.job = job.id AND job_finish.result = ?3) FROM job \
+     WHERE task = ?1 AND requeued AND COALESCE(retry_at, 0) <= ?2 \
+       AND NOT EXISTS (SELECT 1 FROM job AS r WHERE r.retry = job.id) \
+     ORDER BY id LIMIT 1";
+    let now = now();
+    reclaim_expired(conn, now)?;
+    let mut job_q = conn.prepare(JOB_Q)?;
+    let mut jobs = job_q.query([now])?;
+    let row = loop {
+        match jobs.next()? {
+            Some(row) if blockers(conn, row.get(0)?)?.is_empty() => break row,
+            Some(_) => continue,
+            None => return Ok(None),
+        }
+    };
+    let job = Job {
+        id: row.get(0)?,
+        data: row.get(1)?,
+    };
+    let retry: Option<(JobId, u32)> = conn
+        .prepare(RETRY_Q)?
+        .query(params![job.id, now, RESULT_PREEMPTED])?
+        .next()?
+        .map(|row| (row.get(0).unwrap(), row.get(1).unwrap()));
+    let (retry, attempt) = match retry {
+        Some((retry, attempt)) => (Some(retry), attempt + 1),
+        None => (None, 1),
+    };
+    let lease = lease.map(|lease| lease.as_secs_f64());
+    conn.execute(
+        "INSERT INTO job (task, worker, time, attempt, retry, lease, lease_expires) \
+         VALUES (?, ?, ?, ?, ?, ?, ?)",
+        params![
+            job.id,
+            worker,
+            now,
+            attempt,
+            retry,
+            lease,
+            lease.map(|lease| now + lease)
+        ],
+    )?;
+    let id = conn.last_insert_rowid() as JobId;
+    Ok(Some((job, id)))
+}
+
+/// See [`Db::get_repetition`].
+fn repetition(conn: &Connection, job: JobId) -> Result<u64> {
+    Ok(conn.query_row(
+        "WITH RECURSIVE chain(id, task, retry) AS ( \
+           SELECT id, task, retry FROM job WHERE id = ?1 \
+           UNION ALL SELECT job.id, job.task, job.retry FROM job JOIN chain ON job.id = chain.retry) \
+         SELECT count(1) FROM job, chain \
+         WHERE chain.retry IS NULL AND job.task = chain.task AND job.retry IS NULL \
+           AND job.id < chain.id",
+        [job],
+        |row| row.get(0),
+    )?)
+}
+
+fn job_context(conn: &Connection, job: JobId) -> Result<JobContext> {
+    let (task, worker, data) = conn.query_row(
+        "SELECT task.id, job.worker, task.data FROM job JOIN task ON task.id = job.task \
+         WHERE job.id = ?",
+        [job],
+        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+    )?;
+    Ok(JobContext {
+        job,
+        task,
+        repetition: repetition(conn, job)?,
+        worker,
+        data,
+    })
+}
+
 impl Db {
     pub fn create(path: &str) -> Result<Self> {
         // TODO: fail right away if the path exists--would give a clearer error message than
@@ -114,13 +602,30 @@ impl Db {
         prepare_conn(&conn)?;
 
         conn.execute("CREATE TABLE meta (version INTEGER)", [])?;
-        conn.execute("CREATE TABLE task (id INTEGER PRIMARY KEY, count INTEGER NOT NULL, data BLOB NOT NULL, priority INTEGER)", [])?;
-        conn.execute("CREATE TABLE job (id INTEGER PRIMARY KEY, task REFERENCES task, time INTEGER, worker TEXT NOT NULL)", [])?;
+        conn.execute("CREATE TABLE task (id INTEGER PRIMARY KEY, count INTEGER NOT NULL, data BLOB NOT NULL, priority INTEGER, \
+                      max_attempts INTEGER, retry_delay REAL, retry_backoff REAL, time REAL, \
+                      timeout REAL, max_memory INTEGER, max_cpu_time REAL, nice INTEGER, \
+                      preemptible INTEGER NOT NULL DEFAULT 0)", [])?;
+        conn.execute("CREATE TABLE job (id INTEGER PRIMARY KEY, task REFERENCES task, time REAL, worker TEXT NOT NULL, \
+                      requeued INTEGER NOT NULL DEFAULT 0, attempt INTEGER NOT NULL DEFAULT 1, retry REFERENCES job, retry_at REAL, \
+                      failed INTEGER NOT NULL DEFAULT 0, lease REAL, lease_expires REAL, \
+                      pid INTEGER, host TEXT, pid_start INTEGER, cancel INTEGER, suspend INTEGER NOT NULL DEFAULT 0, \
+                      preempted INTEGER NOT NULL DEFAULT 0)", [])?;
         conn.execute(
-            "CREATE TABLE job_start (job PRIMARY KEY REFERENCES job, time INTEGER, cmd BLOB)",
+            "CREATE TABLE job_start (job PRIMARY KEY REFERENCES job, time REAL, cmd BLOB)",
+            [],
+        )?;
+        conn.execute("CREATE TABLE job_finish (job PRIMARY KEY REFERENCES job, result INTEGER, time REAL, data BLOB, \
+                      user_time REAL, system_time REAL, max_rss INTEGER, in_blocks INTEGER, out_blocks INTEGER)", [])?;
+        conn.execute(
+            "CREATE TABLE task_dep (task REFERENCES task, after REFERENCES task, condition TEXT NOT NULL, \
+         PRIMARY KEY (task, after))",
+            [],
+        )?;
+        conn.execute(
+            "CREATE TABLE job_suspend (job REFERENCES job, start REAL NOT NULL, end REAL)",
             [],
         )?;
-        conn.execute("CREATE TABLE job_finish (job PRIMARY KEY REFERENCES job, result INTEGER, time INTEGER, data BLOB)", [])?;
         conn.execute("INSERT INTO meta VALUES (?)", [DB_VERSION])?;
 
         Ok(Self { conn })
@@ -138,46 +643,233 @@ impl Db {
     }
 
     pub fn take(&mut self, worker: &str) -> Result<Option<Job>> {
-        const JOB_Q: &str = "SELECT task.id, task.data FROM task \
-           LEFT JOIN (SELECT job.task, count(1) as c FROM job GROUP BY job.task) as w
-           ON w.task = task.id \
-         WHERE COALESCE(w.c, 0) < task.count \
-         ORDER BY COALESCE(task.priority, 0), task.id LIMIT 1";
-        let job;
-        let tx = self.conn.transaction()?;
-        {
-            let mut job_q = tx.prepare(JOB_Q)?;
-            let mut jobs = job_q.query([])?;
-            let row = match jobs.next()? {
-                Some(row) => row,
-                None => return Ok(None),
-            };
-            job = Job {
-                id: row.get(0)?,
-                data: row.get(1)?,
-            };
-            tx.execute(
-                "INSERT INTO job (task, worker) VALUES (?, ?)",
-                params![job.id, worker],
-            )?;
-        }
+        self.take_leased(worker, None)
+    }
+
+    /// Take a job. If a lease is given, the job must be renewed with [`Db::heartbeat`] before
+    /// the lease runs out, or its repetition will be put back in the queue.
+    pub fn take_leased(&mut self, worker: &str, lease: Option<Duration>) -> Result<Option<Job>> {
+        Ok(self.take_with_id(worker, lease)?.map(|(job, _)| job))
+    }
+
+    /// Like [`Db::take_leased`], but also returning the id of the job taken; a worker with several
+    /// jobs at once can use it to log them (the other job-logging methods default to the worker's
+    /// latest job, see [`Db::current_job`]).
+    pub fn take_with_id(
+        &mut self,
+        worker: &str,
+        lease: Option<Duration>,
+    ) -> Result<Option<(Job, JobId)>> {
+        let tx = self
+            .conn
+            .transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let job = take_job(&tx, worker, lease)?;
+        tx.commit()?;
+        Ok(job)
+    }
+
+    /// Take a job and log its start, running the given command, in one transaction; so there is
+    /// never a job that has been taken but not started. Placeholders in the command are replaced
+    /// (see [`JobContext::expand`]). Returns the job and the command to run for it.
+    pub fn take_and_start(
+        &mut self,
+        worker: &str,
+        lease: Option<Duration>,
+        cmd: &[Vec<u8>],
+    ) -> Result<Option<(JobContext, Vec<Vec<u8>>)>> {
+        let tx = self
+            .conn
+            .transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let id = match take_job(&tx, worker, lease)? {
+            Some((_, id)) => id,
+            None => return Ok(None),
+        };
+        let context = job_context(&tx, id)?;
+        let cmd: Vec<_> = cmd.iter().map(|arg| context.expand(arg)).collect();
+        tx.execute(
+            "INSERT INTO job_start (job, time, cmd) VALUES (?, ?, ?)",
+            params![id, now(), Command(cmd.clone())],
+        )?;
         tx.commit()?;
+        Ok(Some((context, cmd)))
+    }
+
+    /// Take a job, waiting for one to become available if the queue is empty.
+    ///
+    /// Returns `None` if `timeout` elapses before a job can be taken.
+    pub fn take_blocking(
+        &mut self,
+        worker: &str,
+        lease: Option<Duration>,
+        timeout: Option<Duration>,
+    ) -> Result<Option<Job>> {
+        self.wait_for(timeout, |db| db.take_leased(worker, lease))
+    }
+
+    /// Like [`Db::take_with_id`], but waiting for a job as [`Db::take_blocking`] does.
+    pub fn take_with_id_blocking(
+        &mut self,
+        worker: &str,
+        lease: Option<Duration>,
+        timeout: Option<Duration>,
+    ) -> Result<Option<(Job, JobId)>> {
+        self.wait_for(timeout, |db| db.take_with_id(worker, lease))
+    }
 
-        Ok(Some(job))
+    /// Like [`Db::take_and_start`], but waiting for a job as [`Db::take_blocking`] does.
+    pub fn take_and_start_blocking(
+        &mut self,
+        worker: &str,
+        lease: Option<Duration>,
+        timeout: Option<Duration>,
+        cmd: &[Vec<u8>],
+    ) -> Result<Option<(JobContext, Vec<Vec<u8>>)>> {
+        self.wait_for(timeout, |db| db.take_and_start(worker, lease, cmd))
+    }
+
+    /// Retry taking a job whenever the database changes, until it succeeds or the timeout
+    /// elapses.
+    pub fn wait_for<T>(
+        &mut self,
+        timeout: Option<Duration>,
+        mut take: impl FnMut(&mut Self) -> Result<Option<T>>,
+    ) -> Result<Option<T>> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut last_version = None;
+        let mut last_try = Instant::now();
+        loop {
+            // The data version only changes when another connection commits, so checking it is
+            // much cheaper than retrying the take.
+            let version = self.data_version()?;
+            if last_version != Some(version) || last_try.elapsed() >= RECHECK_INTERVAL {
+                if let Some(job) = take(self)? {
+                    return Ok(Some(job));
+                }
+                last_version = Some(version);
+                last_try = Instant::now();
+            }
+            let mut wait = POLL_INTERVAL;
+            if let Some(deadline) = deadline {
+                let now = Instant::now();
+                if now >= deadline {
+                    return Ok(None);
+                }
+                wait = wait.min(deadline - now);
+            }
+            std::thread::sleep(wait);
+        }
+    }
+
+    fn data_version(&self) -> Result<i64> {
+        Ok(self
+            .conn
+            .query_row("PRAGMA data_version", [], |row| row.get(0))?)
     }
 
     pub fn new_job(&mut self, data: &[u8], count: u64, priority: Option<i32>) -> Result<u32> {
         self.conn.execute(
-            "INSERT INTO task (data, count, priority) VALUES (?, ?, ?)",
-            params![data, count, priority],
+            "INSERT INTO task (data, count, priority, time) VALUES (?, ?, ?, ?)",
+            params![data, count, priority, now()],
         )?;
         let id = self.conn.last_insert_rowid() as TaskId;
 
         Ok(id)
     }
 
+    /// Set how failures of a task's repetitions are retried (`None` = never automatically).
+    pub fn set_retry_policy(&self, task: TaskId, policy: Option<RetryPolicy>) -> Result<()> {
+        let mut q = self.conn.prepare(
+            "UPDATE task SET max_attempts = ?, retry_delay = ?, retry_backoff = ? WHERE id = ?",
+        )?;
+        q.execute(params![
+            policy.map(|p| p.max_attempts),
+            policy.map(|p| p.delay.as_secs_f64()),
+            policy.map(|p| p.backoff),
+            task
+        ])?;
+        Ok(())
+    }
+
+    pub fn get_retry_policy(&self, task: TaskId) -> Result<Option<RetryPolicy>> {
+        get_retry_policy(&self.conn, task)
+    }
+
+    /// Set the limits applied to the commands monitored for a task's repetitions.
+    pub fn set_limits(&self, task: TaskId, limits: &Limits) -> Result<()> {
+        self.conn.execute(
+            "UPDATE task SET timeout = ?, max_memory = ?, max_cpu_time = ?, nice = ? WHERE id = ?",
+            params![
+                limits.timeout.map(|x| x.as_secs_f64()),
+                limits.max_memory,
+                limits.max_cpu_time.map(|x| x.as_secs_f64()),
+                limits.nice,
+                task
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub fn get_limits(&self, task: TaskId) -> Result<Limits> {
+        Ok(self.conn.query_row(
+            "SELECT timeout, max_memory, max_cpu_time, nice FROM task WHERE id = ?",
+            [task],
+            |row| {
+                let timeout: Option<f64> = row.get(0)?;
+                let max_cpu_time: Option<f64> = row.get(2)?;
+                Ok(Limits {
+                    timeout: timeout.map(Duration::from_secs_f64),
+                    max_memory: row.get(1)?,
+                    max_cpu_time: max_cpu_time.map(Duration::from_secs_f64),
+                    nice: row.get(3)?,
+                })
+            },
+        )?)
+    }
+
+    /// Make a task's repetitions wait until all of another task's have finished, with an outcome
+    /// meeting the given condition.
+    pub fn add_dependency(&self, task: TaskId, after: TaskId, condition: Condition) -> Result<()> {
+        anyhow::ensure!(task != after, "task {} can't depend on itself", task);
+        self.conn.execute(
+            "INSERT OR REPLACE INTO task_dep (task, after, condition) VALUES (?, ?, ?)",
+            params![task, after, condition],
+        )?;
+        Ok(())
+    }
+
+    pub fn get_dependencies(&self, task: TaskId) -> Result<Vec<(TaskId, Condition)>> {
+        dependencies(&self.conn, task)
+    }
+
+    /// The dependencies keeping a task's repetitions from being taken.
+    pub fn get_blockers(&self, task: TaskId) -> Result<Vec<Blocker>> {
+        blockers(&self.conn, task)
+    }
+
+    /// Tasks that have repetitions available, other than for unmet dependencies.
+    pub fn blocked_ids_vec(&self) -> Result<Vec<TaskId>> {
+        let mut results = Vec::new();
+        for id in self.available_ids_vec()? {
+            if !self.get_blockers(id)?.is_empty() {
+                results.push(id);
+            }
+        }
+        Ok(results)
+    }
+
     // TODO: iterator version. Has to own its Statement.
     pub fn job_ids_vec(&self) -> Result<Vec<TaskId>> {
+        let mut results = Vec::new();
+        for id in self.available_ids_vec()? {
+            if self.get_blockers(id)?.is_empty() {
+                results.push(id);
+            }
+        }
+        Ok(results)
+    }
+
+    /// Tasks with repetitions available, whether or not their dependencies are met.
+    fn available_ids_vec(&self) -> Result<Vec<TaskId>> {
         let mut q = self
             .conn
             .prepare("SELECT id, count FROM task ORDER BY id")?;
@@ -186,7 +878,7 @@ impl Db {
         while let Some(row) = rows.next()? {
             let id = row.get(0).unwrap();
             let count: u64 = row.get(1).unwrap();
-            let w = self.worker_count(id)?;
+            let w = self.worker_count(id)? + self.backoff_count(id)?;
             if count > w {
                 results.push(id);
             }
@@ -203,18 +895,49 @@ impl Db {
     fn worker_count(&self, job_id: TaskId) -> Result<u64> {
         let mut q_w = self
             .conn
-            .prepare("SELECT count(1) FROM job WHERE task = ?")?;
+            .prepare("SELECT count(1) FROM job WHERE task = ? AND NOT requeued")?;
         let mut w = q_w.query([job_id])?;
         Ok(w.next()?.unwrap().get(0)?)
     }
 
+    /// Number of repetitions waiting out a retry delay.
+    fn backoff_count(&self, task: TaskId) -> Result<u64> {
+        let mut q = self.conn.prepare(
+            "SELECT count(1) FROM job WHERE task = ? AND requeued AND retry_at > ? \
+             AND NOT EXISTS (SELECT 1 FROM job AS r WHERE r.retry = job.id)",
+        )?;
+        let mut n = q.query(params![task, now()])?;
+        Ok(n.next()?.unwrap().get(0)?)
+    }
+
+    /// Whether any repetitions are waiting out a retry delay.
+    pub fn has_pending_retries(&self) -> Result<bool> {
+        let mut q = self.conn.prepare(
+            "SELECT 1 FROM job WHERE requeued AND retry_at > ? \
+             AND NOT EXISTS (SELECT 1 FROM job AS r WHERE r.retry = job.id)",
+        )?;
+        Ok(q.exists([now()])?)
+    }
+
+    /// Abandon unfinished jobs whose leases have expired, putting their repetitions back in the
+    /// queue (subject to their tasks' retry policies). Taking a job does this first; so should
+    /// anything that reports what's available to take.
+    pub fn reclaim_expired(&mut self) -> Result<()> {
+        let tx = self
+            .conn
+            .transaction_with_behavior(TransactionBehavior::Immediate)?;
+        reclaim_expired(&tx, now())?;
+        tx.commit()?;
+        Ok(())
+    }
+
     pub fn get_count(&self, job_id: TaskId) -> Result<u64> {
         let mut q_c = self.conn.prepare("SELECT count FROM task WHERE id = ?")?;
         let mut c = q_c.query([job_id])?;
         let c: u64 = c.next()?.unwrap().get(0)?;
         let w = self.worker_count(job_id)?;
         debug_assert!(c >= w);
-        Ok(if w > c { 0 } else { c - w })
+        Ok(c.saturating_sub(w))
     }
 
     pub fn get_priority(&self, job_id: TaskId) -> Result<i32> {
@@ -235,6 +958,23 @@ impl Db {
         Ok(())
     }
 
+    /// Set whether a task's running jobs may be preempted by more urgent work.
+    pub fn set_preemptible(&self, task: TaskId, preemptible: bool) -> Result<()> {
+        self.conn.execute(
+            "UPDATE task SET preemptible = ? WHERE id = ?",
+            params![preemptible, task],
+        )?;
+        Ok(())
+    }
+
+    pub fn is_preemptible(&self, task: TaskId) -> Result<bool> {
+        Ok(self
+            .conn
+            .query_row("SELECT preemptible FROM task WHERE id = ?", [task], |row| {
+                row.get(0)
+            })?)
+    }
+
     pub fn add_count(&self, task: TaskId, add: i64) -> Result<()> {
         let mut q = self
             .conn
@@ -254,55 +994,368 @@ impl Db {
         })
     }
 
+    /// Whether the given job exists and was taken by the worker.
+    pub fn is_worker_job(&self, worker: &str, job: JobId) -> Result<bool> {
+        Ok(self.conn.query_row(
+            "SELECT count(1) FROM job WHERE id = ? AND worker = ?",
+            params![job, worker],
+            |row| row.get(0),
+        )?)
+    }
+
     pub fn log_start(&mut self, job: JobId, cmd: Vec<Vec<u8>>) -> Result<()> {
         let cmd = Command(cmd);
         self.conn.execute(
-            "INSERT INTO job_start (job, time, cmd) VALUES (?, date('now'), ?)",
-            params![job, cmd],
+            "INSERT INTO job_start (job, time, cmd) VALUES (?, ?, ?)",
+            params![job, now(), cmd],
         )?;
         Ok(())
     }
 
+    /// Log a job's result. If the job failed and its task has a retry policy, its repetition is
+    /// requeued, or marked as failed once it has no attempts left.
     pub fn log_finish(&mut self, job: JobId, result: i32) -> Result<()> {
-        self.conn.execute(
-            "INSERT INTO job_finish (job, result, time) VALUES (?, ?, date('now'))",
-            params![job, result],
-        )?;
-        Ok(())
+        self.log_finish_inner(job, result, None)
     }
 
-    pub fn get_jobs(&mut self) -> Result<Vec<JobId>> {
-        let mut q = self.conn.prepare("SELECT id FROM job ORDER BY id")?;
-        let mut results = Vec::new();
-        let mut rows = q.query([])?;
-        while let Some(row) = rows.next()? {
-            results.push(row.get(0).unwrap());
-        }
-        Ok(results)
+    /// Log a job's result along with some output data, such as the command's output.
+    pub fn log_finish_data(&mut self, job: JobId, result: i32, data: &[u8]) -> Result<()> {
+        self.log_finish_inner(job, result, Some(data))
     }
 
-    pub fn get_started_jobs(&mut self) -> Result<Vec<JobId>> {
-        // get each worker's latest job
-        let mut q0 = self
-            .conn
-            .prepare("SELECT MAX(id) FROM job GROUP BY worker ORDER BY id")?;
-        let mut worker_latest = q0.query([])?;
-        let mut results = Vec::new();
-        while let Some(wl) = worker_latest.next()? {
-            let job = wl.get(0).unwrap();
-            // check if the job is started and not finished
-            let q = "SELECT 1 \
-                     FROM job_start \
-                     LEFT JOIN job_finish \
-                     ON job_start.job = job_finish.job \
-                     WHERE job_finish.job IS NULL \
-                     AND job_start.job = ?";
-            let mut q = self.conn.prepare(q)?;
-            let is_started = q.query([job])?.next()?.is_some();
-            if is_started {
-                results.push(job);
+    fn log_finish_inner(&mut self, job: JobId, result: i32, data: Option<&[u8]>) -> Result<()> {
+        let tx = self.conn.transaction()?;
+        tx.execute(
+            "INSERT INTO job_finish (job, result, time, data) VALUES (?, ?, ?, ?)",
+            params![job, result, now(), data],
+        )?;
+        tx.execute(
+            "UPDATE job_suspend SET end = ? WHERE job = ? AND end IS NULL",
+            params![now(), job],
+        )?;
+        match result {
+            0 | RESULT_CANCELLED => (),
+            // Preemption isn't the job's fault; its repetition is run again without counting as
+            // a failed attempt.
+            RESULT_PREEMPTED => {
+                tx.execute("UPDATE job SET requeued = 1 WHERE id = ?", [job])?;
             }
+            _ => retry_failed(&tx, job)?,
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Record the resources used by a finished job's command.
+    pub fn set_job_usage(&self, job: JobId, usage: &Usage) -> Result<()> {
+        let n = self.conn.execute(
+            "UPDATE job_finish SET user_time = ?, system_time = ?, max_rss = ?, \
+             in_blocks = ?, out_blocks = ? WHERE job = ?",
+            params![
+                usage.user_time.as_secs_f64(),
+                usage.system_time.as_secs_f64(),
+                usage.max_rss,
+                usage.in_blocks,
+                usage.out_blocks,
+                job
+            ],
+        )?;
+        anyhow::ensure!(n == 1, "job {} is not finished", job);
+        Ok(())
+    }
+
+    /// Return a job's repetition to its task's queue.
+    ///
+    /// The job itself remains in the log, marked as requeued. It must have finished, or its lease
+    /// must have expired: otherwise it may still be running, and its repetition would run twice.
+    pub fn requeue(&mut self, job: JobId) -> Result<()> {
+        let n = self.conn.execute(
+            "UPDATE job SET requeued = 1 WHERE id = ?1 \
+             AND (EXISTS (SELECT 1 FROM job_finish WHERE job_finish.job = job.id) \
+               OR lease_expires < ?2)",
+            params![job, now()],
+        )?;
+        if n == 0 {
+            let exists: bool = self.conn.query_row(
+                "SELECT EXISTS (SELECT 1 FROM job WHERE id = ?)",
+                [job],
+                |row| row.get(0),
+            )?;
+            anyhow::ensure!(exists, "no such job: {}", job);
+            anyhow::bail!("job {} hasn't finished, and its lease hasn't expired", job);
+        }
+        Ok(())
+    }
+
+    pub fn is_requeued(&self, job: JobId) -> Result<bool> {
+        Ok(self
+            .conn
+            .query_row("SELECT requeued FROM job WHERE id = ?", [job], |row| {
+                row.get(0)
+            })?)
+    }
+
+    pub fn get_lease(&self, job: JobId) -> Result<Option<Duration>> {
+        let lease: Option<f64> =
+            self.conn
+                .query_row("SELECT lease FROM job WHERE id = ?", [job], |row| {
+                    row.get(0)
+                })?;
+        Ok(lease.map(Duration::from_secs_f64))
+    }
+
+    /// Renew a job's lease. Fails if the lease has already expired and the job was reclaimed.
+    pub fn heartbeat(&self, job: JobId) -> Result<()> {
+        let n = self.conn.execute(
+            "UPDATE job SET lease_expires = ? + lease WHERE id = ? AND NOT requeued \
+             AND NOT EXISTS (SELECT 1 FROM job_finish WHERE job_finish.job = job.id)",
+            params![now(), job],
+        )?;
+        anyhow::ensure!(n == 1, "job {} is no longer active", job);
+        Ok(())
+    }
+
+    /// Ask the monitor running a job to send its command the given signal, and log it as
+    /// cancelled.
+    pub fn cancel(&self, job: JobId, signal: i32) -> Result<()> {
+        let n = self.conn.execute(
+            "UPDATE job SET cancel = ? WHERE id = ? \
+             AND NOT EXISTS (SELECT 1 FROM job_finish WHERE job_finish.job = job.id)",
+            params![signal, job],
+        )?;
+        anyhow::ensure!(n == 1, "job {} is not running", job);
+        Ok(())
+    }
+
+    /// The signal a job's cancellation was requested with, if it was.
+    pub fn get_cancel(&self, job: JobId) -> Result<Option<i32>> {
+        Ok(self
+            .conn
+            .query_row("SELECT cancel FROM job WHERE id = ?", [job], |row| {
+                row.get(0)
+            })?)
+    }
+
+    /// Ask the monitor running a job to stop (`true`) or continue (`false`) its command.
+    pub fn suspend(&self, job: JobId, suspend: bool) -> Result<()> {
+        let n = self.conn.execute(
+            "UPDATE job SET suspend = ? WHERE id = ? \
+             AND NOT EXISTS (SELECT 1 FROM job_finish WHERE job_finish.job = job.id)",
+            params![suspend, job],
+        )?;
+        anyhow::ensure!(n == 1, "job {} is not running", job);
+        Ok(())
+    }
+
+    /// Whether a job's command has been asked to stop, and not to continue since.
+    pub fn is_suspended(&self, job: JobId) -> Result<bool> {
+        Ok(self
+            .conn
+            .query_row("SELECT suspend FROM job WHERE id = ?", [job], |row| {
+                row.get(0)
+            })?)
+    }
+
+    /// Record that a job's command has been stopped (`true`) or continued (`false`), so that the
+    /// time it spends stopped isn't counted as run time.
+    pub fn log_suspended(&self, job: JobId, suspended: bool) -> Result<()> {
+        if suspended {
+            self.conn.execute(
+                "INSERT INTO job_suspend (job, start) VALUES (?, ?)",
+                params![job, now()],
+            )?;
+        } else {
+            self.conn.execute(
+                "UPDATE job_suspend SET end = ? WHERE job = ? AND end IS NULL",
+                params![now(), job],
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Make room for a task's queued repetitions by cancelling running jobs of preemptible tasks
+    /// with a higher priority number, sending their commands the given signal. The least urgent
+    /// jobs, and of those the most recently started, are preempted first; at most one for each of
+    /// the task's queued repetitions. Nothing is preempted for a task that is blocked by its
+    /// dependencies. Preempted jobs are logged with [`RESULT_PREEMPTED`] by their monitors, and
+    /// their repetitions requeued.
+    ///
+    /// Idle workers waiting for jobs get the first chance to take the task's repetitions: this
+    /// waits up to `wait` for them to, and only preempts for the repetitions still queued after
+    /// that. Waiting workers notice a new task within a second (see [`Db::wait_for`]).
+    ///
+    /// Returns the jobs preempted.
+    pub fn preempt(&mut self, task: TaskId, signal: i32, wait: Duration) -> Result<Vec<JobId>> {
+        self.wait_for(Some(wait), |db| {
+            Ok((queued_repetitions(&db.conn, task)? == 0).then_some(()))
+        })?;
+        let tx = self
+            .conn
+            .transaction_with_behavior(TransactionBehavior::Immediate)?;
+        if !blockers(&tx, task)?.is_empty() {
+            return Ok(Vec::new());
         }
+        let priority: i32 = tx.query_row(
+            "SELECT COALESCE(priority, 0) FROM task WHERE id = ?",
+            [task],
+            |row| row.get(0),
+        )?;
+        let preempted = tx
+            .prepare(
+                "SELECT job.id FROM job \
+                 JOIN task ON task.id = job.task \
+                 JOIN job_start ON job_start.job = job.id \
+                 WHERE task.preemptible AND COALESCE(task.priority, 0) > ? \
+                   AND NOT job.requeued AND job.cancel IS NULL \
+                   AND NOT EXISTS (SELECT 1 FROM job_finish WHERE job_finish.job = job.id) \
+                 ORDER BY COALESCE(task.priority, 0) DESC, job_start.time DESC LIMIT ?",
+            )?
+            .query_map(params![priority, queued_repetitions(&tx, task)?], |row| {
+                row.get(0)
+            })?
+            .collect::<rusqlite::Result<Vec<JobId>>>()?;
+        for job in &preempted {
+            tx.execute(
+                "UPDATE job SET cancel = ?, preempted = 1 WHERE id = ?",
+                params![signal, job],
+            )?;
+        }
+        tx.commit()?;
+        Ok(preempted)
+    }
+
+    /// Whether a job has been cancelled by [`Db::preempt`].
+    pub fn is_preempted(&self, job: JobId) -> Result<bool> {
+        Ok(self
+            .conn
+            .query_row("SELECT preempted FROM job WHERE id = ?", [job], |row| {
+                row.get(0)
+            })?)
+    }
+
+    /// Total time a job has spent suspended.
+    pub fn get_paused_time(&self, job: JobId) -> Result<Duration> {
+        let paused: f64 = self.conn.query_row(
+            &format!("SELECT {} FROM job WHERE id = ?2", paused_time("?1")),
+            params![now(), job],
+            |row| row.get(0),
+        )?;
+        Ok(Duration::from_secs_f64(paused))
+    }
+
+    /// Record the process responsible for a job, so the job can be reaped if the process exits
+    /// without finishing it.
+    pub fn set_job_process(&self, job: JobId, process: &Process) -> Result<()> {
+        self.conn.execute(
+            "UPDATE job SET pid = ?, host = ?, pid_start = ? WHERE id = ?",
+            params![process.pid, process.host, process.start_time, job],
+        )?;
+        Ok(())
+    }
+
+    pub fn get_job_process(&self, job: JobId) -> Result<Option<Process>> {
+        Ok(self.conn.query_row(
+            "SELECT pid, host, pid_start FROM job WHERE id = ?",
+            [job],
+            |row| {
+                let pid: Option<u32> = row.get(0)?;
+                Ok(match pid {
+                    Some(pid) => Some(Process {
+                        pid,
+                        host: row.get(1)?,
+                        start_time: row.get(2)?,
+                    }),
+                    None => None,
+                })
+            },
+        )?)
+    }
+
+    /// Find unfinished jobs whose processes ran on this host and no longer exist, log them as
+    /// abandoned, and requeue them. Returns the jobs reaped.
+    pub fn reap(&mut self) -> Result<Vec<JobId>> {
+        let host = process::hostname()?;
+        let tx = self
+            .conn
+            .transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let candidates = tx
+            .prepare(
+                "SELECT id, pid, pid_start FROM job WHERE host = ? AND NOT requeued \
+                 AND NOT EXISTS (SELECT 1 FROM job_finish WHERE job_finish.job = job.id)",
+            )?
+            .query_map([&host], |row| {
+                Ok((
+                    row.get(0)?,
+                    Process {
+                        pid: row.get(1)?,
+                        host: host.clone(),
+                        start_time: row.get(2)?,
+                    },
+                ))
+            })?
+            .collect::<rusqlite::Result<Vec<(JobId, Process)>>>()?;
+        let mut reaped = Vec::new();
+        for (job, process) in candidates {
+            if !process.is_alive()? {
+                abandon(&tx, job, RESULT_ABANDONED)?;
+                reaped.push(job);
+            }
+        }
+        tx.commit()?;
+        Ok(reaped)
+    }
+
+    /// Give up on a job that won't be finished normally (e.g. because its monitor is exiting):
+    /// unless it has already finished, log it as abandoned and requeue it, as [`Db::reap`] would.
+    pub fn abandon(&mut self, job: JobId) -> Result<()> {
+        let tx = self
+            .conn
+            .transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let finished: bool = tx.query_row(
+            "SELECT EXISTS (SELECT 1 FROM job_finish WHERE job = ?)",
+            [job],
+            |row| row.get(0),
+        )?;
+        if !finished {
+            abandon(&tx, job, RESULT_ABANDONED)?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Whether a job's repetition failed permanently, having used up its task's retries.
+    pub fn is_failed(&self, job: JobId) -> Result<bool> {
+        Ok(self
+            .conn
+            .query_row("SELECT failed FROM job WHERE id = ?", [job], |row| {
+                row.get(0)
+            })?)
+    }
+
+    pub fn get_jobs(&mut self) -> Result<Vec<JobId>> {
+        let mut q = self.conn.prepare("SELECT id FROM job ORDER BY id")?;
+        let mut results = Vec::new();
+        let mut rows = q.query([])?;
+        while let Some(row) = rows.next()? {
+            results.push(row.get(0).unwrap());
+        }
+        Ok(results)
+    }
+
+    pub fn get_started_jobs(&mut self) -> Result<Vec<JobId>> {
+        // A worker may be running several jobs at once, so this is every job that's started and
+        // not finished.
+        let mut q = self.conn.prepare(
+            "SELECT job_start.job \
+             FROM job_start \
+             LEFT JOIN job_finish \
+             ON job_start.job = job_finish.job \
+             WHERE job_finish.job IS NULL \
+             ORDER BY job_start.job",
+        )?;
+        let results = q
+            .query_map([], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
         Ok(results)
     }
 
@@ -327,6 +1380,35 @@ impl Db {
             .get(0)?)
     }
 
+    /// When a job was taken, if known.
+    pub fn get_job_time(&self, job: JobId) -> Result<Option<Time>> {
+        let time: Option<f64> =
+            self.conn
+                .query_row("SELECT time FROM job WHERE id = ?", [job], |row| row.get(0))?;
+        Ok(time.map(Time))
+    }
+
+    /// When a task was created, if known.
+    pub fn get_task_time(&self, task: TaskId) -> Result<Option<Time>> {
+        let time: Option<f64> =
+            self.conn
+                .query_row("SELECT time FROM task WHERE id = ?", [task], |row| {
+                    row.get(0)
+                })?;
+        Ok(time.map(Time))
+    }
+
+    /// Which of its task's repetitions a job is doing, counting from 0. Retries and requeues of a
+    /// repetition share its number.
+    pub fn get_repetition(&self, job: JobId) -> Result<u64> {
+        repetition(&self.conn, job)
+    }
+
+    /// Everything a job's command can refer to with placeholders.
+    pub fn get_job_context(&self, job: JobId) -> Result<JobContext> {
+        job_context(&self.conn, job)
+    }
+
     pub fn get_job_start(&self, job: JobId) -> Result<Option<Start>> {
         Ok(self
             .conn
@@ -334,7 +1416,7 @@ impl Db {
             .query([job])?
             .next()?
             .map(|row| Start {
-                time: Time(row.get(0).unwrap()),
+                time: row.get::<_, Option<f64>>(0).unwrap().map(Time),
                 cmd: row.get(1).unwrap(),
             }))
     }
@@ -342,27 +1424,388 @@ impl Db {
     pub fn get_job_finish(&self, job: JobId) -> Result<Option<Finish>> {
         Ok(self
             .conn
-            .prepare("SELECT time, result, data FROM job_finish WHERE job = ?")?
+            .prepare(
+                "SELECT time, result, data, \
+                   user_time, system_time, max_rss, in_blocks, out_blocks \
+                 FROM job_finish WHERE job = ?",
+            )?
             .query([job])?
             .next()?
             .map(|row| Finish {
-                time: Time(row.get(0).unwrap()),
+                time: row.get::<_, Option<f64>>(0).unwrap().map(Time),
                 result: row.get(1).unwrap(),
-                data: row.get(2).unwrap(),
+                data: row
+                    .get::<_, Option<Vec<u8>>>(2)
+                    .unwrap()
+                    .unwrap_or_default(),
+                usage: row
+                    .get::<_, Option<f64>>(3)
+                    .unwrap()
+                    .map(|user_time| Usage {
+                        user_time: Duration::from_secs_f64(user_time),
+                        system_time: Duration::from_secs_f64(row.get(4).unwrap()),
+                        max_rss: row.get(5).unwrap(),
+                        in_blocks: row.get(6).unwrap(),
+                        out_blocks: row.get(7).unwrap(),
+                    }),
             }))
     }
+
+    /// Gather statistics about each task's repetitions and jobs.
+    pub fn task_stats(&self) -> Result<Vec<TaskStats>> {
+        let mut stats = BTreeMap::new();
+        for task in self.task_ids_vec()? {
+            stats.insert(
+                task,
+                (
+                    TaskStats {
+                        task,
+                        queued: self.get_count(task)?,
+                        ..Default::default()
+                    },
+                    Samples::default(),
+                ),
+            );
+        }
+        // A job's repetition became available when its previous attempt was requeued (or, if it
+        // was backing off, when it could be retried); or if it's the first attempt, when its task
+        // was created or its dependencies last finished. Run times don't include time spent
+        // suspended.
+        let mut q = self.conn.prepare(&format!(
+            "SELECT job.task, job.requeued, \
+               job.time - CASE WHEN job.retry IS NULL \
+                 THEN max(task.time, COALESCE((SELECT max(f.time) FROM task_dep \
+                   JOIN job AS d ON d.task = task_dep.after \
+                   JOIN job_finish AS f ON f.job = d.id \
+                   WHERE task_dep.task = task.id), task.time)) \
+                 ELSE COALESCE(prev.retry_at, prev_finish.time, prev.lease_expires) END, \
+               job_start.job IS NOT NULL, job_finish.time - job_start.time - {}, job_finish.result, \
+               job_finish.user_time + job_finish.system_time, job_finish.max_rss \
+             FROM job JOIN task ON job.task = task.id \
+             LEFT JOIN job AS prev ON prev.id = job.retry \
+             LEFT JOIN job_finish AS prev_finish ON prev_finish.job = prev.id \
+             LEFT JOIN job_start ON job_start.job = job.id \
+             LEFT JOIN job_finish ON job_finish.job = job.id",
+            paused_time("job_finish.time")
+        ))?;
+        let mut rows = q.query([])?;
+        while let Some(row) = rows.next()? {
+            let task: TaskId = row.get(0)?;
+            let requeued: bool = row.get(1)?;
+            let wait: Option<f64> = row.get(2)?;
+            let started: bool = row.get(3)?;
+            let run_time: Option<f64> = row.get(4)?;
+            let result: Option<i32> = row.get(5)?;
+            let cpu_time: Option<f64> = row.get(6)?;
+            let max_rss: Option<f64> = row.get(7)?;
+            let (stats, samples) = stats.get_mut(&task).unwrap();
+            samples.queue_wait.extend(wait);
+            samples.run_time.extend(run_time);
+            samples.cpu_time.extend(cpu_time);
+            samples.max_rss.extend(max_rss);
+            // A requeued job's repetition is counted wherever its retry is.
+            if requeued {
+                continue;
+            }
+            match (started, result) {
+                (_, Some(0)) => stats.succeeded += 1,
+                (_, Some(_)) => stats.failed += 1,
+                (true, None) => stats.running += 1,
+                (false, None) => stats.taken += 1,
+            }
+        }
+        Ok(stats
+            .into_values()
+            .map(|(stats, mut samples)| TaskStats {
+                run_time: Summary::of(&mut samples.run_time),
+                queue_wait: Summary::of(&mut samples.queue_wait),
+                cpu_time: Summary::of(&mut samples.cpu_time),
+                max_rss: Summary::of(&mut samples.max_rss),
+                ..stats
+            })
+            .collect())
+    }
+
+    /// Estimate when each task with work remaining, and the whole queue, will be drained.
+    ///
+    /// This assumes that jobs take as long as the mean run time of their task (or of all tasks,
+    /// for a task without any finished jobs), that as many workers as are currently running jobs
+    /// will keep taking them in priority order, and that no more work will be added.
+    pub fn eta(&mut self) -> Result<Eta> {
+        let now = now();
+        let workers = self.get_started_jobs()?.len();
+        let stats = self.task_stats()?;
+        let mut all_run_times = self
+            .conn
+            .prepare(&format!(
+                "SELECT job_finish.time - job_start.time - {} FROM job \
+                 JOIN job_start ON job_start.job = job.id \
+                 JOIN job_finish ON job_finish.job = job.id \
+                 WHERE job_start.time IS NOT NULL AND job_finish.time IS NOT NULL",
+                paused_time("job_finish.time")
+            ))?
+            .query_map([], |row| row.get(0))?
+            .collect::<rusqlite::Result<Vec<f64>>>()?;
+        let default_mean = Summary::of(&mut all_run_times).map(|s| s.mean);
+        let mut elapsed: BTreeMap<TaskId, Vec<f64>> = BTreeMap::new();
+        let mut q = self.conn.prepare(&format!(
+            "SELECT job.task, ?1 - job_start.time - {} FROM job \
+             JOIN job_start ON job_start.job = job.id \
+             WHERE NOT job.requeued AND job_start.time IS NOT NULL \
+             AND NOT EXISTS (SELECT 1 FROM job_finish WHERE job_finish.job = job.id)",
+            paused_time("?1")
+        ))?;
+        let mut rows = q.query([now])?;
+        while let Some(row) = rows.next()? {
+            elapsed.entry(row.get(0)?).or_default().push(row.get(1)?);
+        }
+
+        let mut tasks = Vec::new();
+        for s in stats {
+            let remaining = s.queued + s.taken + s.running;
+            if remaining == 0 {
+                continue;
+            }
+            let priority = self.get_priority(s.task)?;
+            let mean = s.run_time.map(|s| s.mean).or(default_mean);
+            let elapsed = elapsed.remove(&s.task).unwrap_or_default();
+            tasks.push((
+                priority,
+                s.task,
+                remaining,
+                s.queued + s.taken,
+                mean,
+                elapsed,
+            ));
+        }
+        tasks.sort_by_key(|&(priority, task, ..)| (priority, task));
+
+        fn running_left(
+            mean: Option<f64>,
+            elapsed: &[f64],
+        ) -> Option<impl Iterator<Item = f64> + '_> {
+            mean.map(|mean| elapsed.iter().map(move |e| (mean - e).max(0.0)))
+        }
+        // The work left on running jobs gets done first, then queued jobs in take order.
+        let mut work: Option<f64> = tasks
+            .iter()
+            .map(|(.., mean, elapsed)| running_left(*mean, elapsed).map(Iterator::sum::<f64>))
+            .sum();
+        let mut task_etas = Vec::new();
+        let mut drained = Some(Time(now));
+        for (_, task, remaining, queued, mean, elapsed) in tasks {
+            // A task's running jobs finish in parallel with everything else.
+            let left = running_left(mean, &elapsed).map(|left| left.fold(0.0, f64::max));
+            let task_drained = if queued == 0 {
+                left.map(|left| Time(now + left))
+            } else {
+                work = work
+                    .zip(mean)
+                    .map(|(work, mean)| work + queued as f64 * mean);
+                match (work, left) {
+                    (Some(work), Some(left)) if workers > 0 => {
+                        Some(Time(now + (work / workers as f64).max(left)))
+                    }
+                    _ => None,
+                }
+            };
+            drained = drained
+                .zip(task_drained)
+                .map(|(a, b)| if b > a { b } else { a });
+            task_etas.push(TaskEta {
+                task,
+                remaining,
+                drained: task_drained,
+            });
+        }
+        Ok(Eta {
+            tasks: task_etas,
+            workers,
+            drained,
+        })
+    }
+
+    fn task_ids_vec(&self) -> Result<Vec<TaskId>> {
+        let mut q = self.conn.prepare("SELECT id FROM task ORDER BY id")?;
+        let ids = q
+            .query_map([], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(ids)
+    }
+}
+
+/// An unmet dependency of a task.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Blocker {
+    /// The task depended on.
+    pub after: TaskId,
+    pub reason: BlockReason,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum BlockReason {
+    /// The dependency has repetitions that haven't finished.
+    Unfinished,
+    /// Some of the dependency's repetitions failed, so an `afterok` dependency will never be met.
+    Failed,
+    /// All of the dependency's repetitions succeeded, so an `afternotok` dependency will never be
+    /// met.
+    Succeeded,
+}
+
+impl Display for Blocker {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.reason {
+            BlockReason::Unfinished => write!(f, "waiting for {}", self.after),
+            BlockReason::Failed => write!(f, "{} failed", self.after),
+            BlockReason::Succeeded => write!(f, "{} succeeded", self.after),
+        }
+    }
+}
+
+/// What a dependency's outcome must be for a task depending on it to run. Any non-zero result
+/// (including signals, and commands that failed to start) counts as failure.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Condition {
+    /// All repetitions succeeded.
+    Ok,
+    /// All repetitions finished, and at least one failed.
+    NotOk,
+    /// All repetitions finished.
+    Any,
+}
+
+impl Condition {
+    fn name(self) -> &'static str {
+        match self {
+            Condition::Ok => "afterok",
+            Condition::NotOk => "afternotok",
+            Condition::Any => "afterany",
+        }
+    }
+}
+
+impl Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromSql for Condition {
+    fn column_result(value: ValueRef) -> FromSqlResult<Self> {
+        match value.as_str()? {
+            "afterok" => Ok(Condition::Ok),
+            "afternotok" => Ok(Condition::NotOk),
+            "afterany" => Ok(Condition::Any),
+            _ => Err(FromSqlError::InvalidType),
+        }
+    }
+}
+
+impl ToSql for Condition {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(self.name().into())
+    }
+}
+
+/// Counts of a task's repetitions in each state, and its jobs' timings.
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct TaskStats {
+    pub task: TaskId,
+    /// Repetitions not yet taken.
+    pub queued: u64,
+    /// Repetitions taken, but not logged as started.
+    pub taken: u64,
+    /// Repetitions started and not finished.
+    pub running: u64,
+    pub succeeded: u64,
+    /// Repetitions that failed and were not requeued.
+    pub failed: u64,
+    /// Time from start to finish of each finished job, including failed attempts.
+    pub run_time: Option<Summary>,
+    /// Time from each job's repetition becoming available (after the task's creation and its
+    /// dependencies, or the previous attempt being requeued and backing off) to its being taken.
+    pub queue_wait: Option<Summary>,
+    /// User plus system CPU time of each finished job whose usage was recorded.
+    pub cpu_time: Option<Summary>,
+    /// Maximum resident set size, in bytes, of each finished job whose usage was recorded.
+    pub max_rss: Option<Summary>,
+}
+
+/// Measurements summarized in [`TaskStats`].
+#[derive(Default)]
+struct Samples {
+    run_time: Vec<f64>,
+    queue_wait: Vec<f64>,
+    cpu_time: Vec<f64>,
+    max_rss: Vec<f64>,
+}
+
+/// Estimated completion times for the work remaining in the queue.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Eta {
+    /// Tasks with repetitions remaining, in the order they'll be taken.
+    pub tasks: Vec<TaskEta>,
+    /// Number of workers assumed to be working through the queue.
+    pub workers: usize,
+    /// When the last repetition is expected to finish, if it can be estimated.
+    pub drained: Option<Time>,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct TaskEta {
+    pub task: TaskId,
+    /// Repetitions not yet finished.
+    pub remaining: u64,
+    /// When the task's last repetition is expected to finish, if it can be estimated.
+    pub drained: Option<Time>,
+}
+
+/// Summary statistics of a set of durations, in seconds.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Summary {
+    pub mean: f64,
+    pub median: f64,
+    pub p95: f64,
+}
+
+impl Summary {
+    /// Summarize some samples (reordering them in the process), if there are any.
+    pub fn of(samples: &mut [f64]) -> Option<Self> {
+        if samples.is_empty() {
+            return None;
+        }
+        samples.sort_by(f64::total_cmp);
+        // nearest-rank percentile
+        let percentile = |p: f64| samples[((p * samples.len() as f64).ceil() as usize).max(1) - 1];
+        Some(Summary {
+            mean: samples.iter().sum::<f64>() / samples.len() as f64,
+            median: percentile(0.5),
+            p95: percentile(0.95),
+        })
+    }
 }
 
 mod time_ {
     use std::fmt;
     use time::{OffsetDateTime, UtcOffset};
 
-    #[derive(Copy, Clone)]
-    pub struct Time(pub i64);
+    /// A unix timestamp, in seconds.
+    #[derive(Copy, Clone, PartialEq, PartialOrd, Debug)]
+    pub struct Time(pub f64);
+
+    impl Time {
+        /// Time elapsed since an earlier time, in seconds.
+        pub fn since(self, earlier: Time) -> f64 {
+            self.0 - earlier.0
+        }
+    }
 
     impl fmt::Display for Time {
         fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-            let mut t = match OffsetDateTime::from_unix_timestamp(self.0) {
+            // Millisecond precision is plenty for display.
+            let millis = (self.0 * 1e3).round() as i128;
+            let mut t = match OffsetDateTime::from_unix_timestamp_nanos(millis * 1_000_000) {
                 Ok(t) => t,
                 Err(_) => return write!(f, "<invalid timestamp>"),
             };
@@ -375,6 +1818,100 @@ mod time_ {
 }
 pub use time_::Time;
 
+mod process {
+    use crate::Result;
+    use anyhow::Context;
+
+    /// A process on a particular host. The process's start time distinguishes it from a later
+    /// process that reuses its pid.
+    #[derive(Clone, PartialEq, Eq, Debug)]
+    pub struct Process {
+        pub pid: u32,
+        pub host: String,
+        /// Start time, in clock ticks after boot (0 where that can't be found).
+        pub start_time: u64,
+    }
+
+    impl Process {
+        /// The current process.
+        pub fn current() -> Result<Self> {
+            Self::from_pid(std::process::id())?.context("current process not found")
+        }
+
+        /// The parent of the current process.
+        pub fn parent() -> Result<Self> {
+            Self::from_pid(std::os::unix::process::parent_id())?.context("parent process not found")
+        }
+
+        /// The process on this host with the given pid, if it exists.
+        pub fn from_pid(pid: u32) -> Result<Option<Self>> {
+            let start_time = match start_time(pid)? {
+                Some(start_time) => start_time,
+                None => return Ok(None),
+            };
+            Ok(Some(Process {
+                pid,
+                host: hostname()?,
+                start_time,
+            }))
+        }
+
+        /// Whether the process still exists. Only processes on this host can be checked.
+        pub fn is_alive(&self) -> Result<bool> {
+            anyhow::ensure!(
+                self.host == hostname()?,
+                "process {} is on another host ({})",
+                self.pid,
+                self.host
+            );
+            Ok(start_time(self.pid)? == Some(self.start_time))
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    pub(crate) fn hostname() -> Result<String> {
+        let hostname = std::fs::read_to_string("/proc/sys/kernel/hostname")?;
+        Ok(hostname.trim_end().to_owned())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub(crate) fn hostname() -> Result<String> {
+        let mut buf = [0u8; 256];
+        if unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) } != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+        Ok(String::from_utf8_lossy(&buf[..len]).into_owned())
+    }
+
+    #[cfg(target_os = "linux")]
+    fn start_time(pid: u32) -> Result<Option<u64>> {
+        let stat = match std::fs::read_to_string(format!("/proc/{}/stat", pid)) {
+            Ok(stat) => stat,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        // The command name is in parentheses and may itself contain spaces and parentheses;
+        // starttime is the 20th field after it.
+        let fields = stat.rsplit_once(')').context("malformed /proc stat")?.1;
+        let start_time = fields
+            .split_whitespace()
+            .nth(19)
+            .context("malformed /proc stat")?;
+        Ok(Some(start_time.parse()?))
+    }
+
+    /// Without /proc, we can only tell whether some process has the pid, so a process that reuses
+    /// a dead one's pid is taken for it.
+    #[cfg(not(target_os = "linux"))]
+    fn start_time(pid: u32) -> Result<Option<u64>> {
+        let exists = unsafe { libc::kill(pid as libc::pid_t, 0) } == 0
+            || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM);
+        Ok(if exists { Some(0) } else { None })
+    }
+}
+pub use process::Process;
+
 #[derive(Serialize, Deserialize)]
 pub struct Command(Vec<Vec<u8>>);
 
@@ -390,7 +1927,7 @@ impl fmt::Display for Command {
             Err(_) => write!(f, "<binary>")?,
         }
         for arg in args {
-            match std::str::from_utf8(&arg) {
+            match std::str::from_utf8(arg) {
                 Ok(s) => write!(f, " {:?}", s)?,
                 Err(_) => write!(f, " <binary>")?,
             }
@@ -403,14 +1940,14 @@ impl FromSql for Command {
     fn column_result(value: ValueRef) -> FromSqlResult<Self> {
         Vec::<u8>::column_result(value).and_then(|blob| {
             bincode::deserialize(&blob)
-                .map(|vec| Command(vec))
+                .map(Command)
                 .map_err(|e| FromSqlError::Other(Box::new(e)))
         })
     }
 }
 
 impl ToSql for Command {
-    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput> {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
         Ok(bincode::serialize(&self.0)
             .map_err(|e| FromSqlError::Other(Box::new(e)))?
             .into())
@@ -418,16 +1955,83 @@ impl ToSql for Command {
 }
 
 pub struct Start {
-    pub time: Time,
+    /// Unknown for jobs logged by versions that only recorded the day.
+    pub time: Option<Time>,
     pub cmd: Command,
 }
 
 pub struct Finish {
     pub result: i32,
-    pub time: Time,
+    /// Unknown for jobs logged by versions that only recorded the day.
+    pub time: Option<Time>,
+    pub data: Vec<u8>,
+    /// Resources used by the command, if they were recorded.
+    pub usage: Option<Usage>,
+}
+
+/// Resources used by a job's command, including any descendants it waited for.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct Usage {
+    pub user_time: Duration,
+    pub system_time: Duration,
+    /// Maximum resident set size, in bytes.
+    pub max_rss: u64,
+    /// Number of blocks read from the filesystem.
+    pub in_blocks: u64,
+    /// Number of blocks written to the filesystem.
+    pub out_blocks: u64,
+}
+
+/// A job, as seen by the command run for it.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct JobContext {
+    pub job: JobId,
+    pub task: TaskId,
+    /// See [`Db::get_repetition`].
+    pub repetition: u64,
+    pub worker: String,
     pub data: Vec<u8>,
 }
 
+/// The placeholders [`JobContext::expand`] substitutes.
+const PLACEHOLDERS: &[&[u8]] = &[b"{data}", b"{task}", b"{job}", b"{rep}", b"{worker}"];
+
+/// Whether a command argument contains any placeholders.
+pub fn has_placeholders(arg: &[u8]) -> bool {
+    PLACEHOLDERS
+        .iter()
+        .any(|p| arg.windows(p.len()).any(|w| w == *p))
+}
+
+impl JobContext {
+    /// Substitute the job's values for `{data}`, `{task}`, `{job}`, `{rep}` and `{worker}` in a
+    /// command argument. Anything else, including other text in braces, is left as is.
+    pub fn expand(&self, arg: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(arg.len());
+        let mut rest = arg;
+        'scan: while !rest.is_empty() {
+            if rest[0] == b'{' {
+                for &p in PLACEHOLDERS {
+                    if rest.starts_with(p) {
+                        match p {
+                            b"{data}" => out.extend_from_slice(&self.data),
+                            b"{task}" => out.extend(self.task.to_string().bytes()),
+                            b"{job}" => out.extend(self.job.to_string().bytes()),
+                            b"{rep}" => out.extend(self.repetition.to_string().bytes()),
+                            _ => out.extend(self.worker.bytes()),
+                        }
+                        rest = &rest[p.len()..];
+                        continue 'scan;
+                    }
+                }
+            }
+            out.push(rest[0]);
+            rest = &rest[1..];
+        }
+        out
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -447,7 +2051,7 @@ mod test {
         let conn = Connection::open_in_memory()?;
         let db = Db::create_from_conn(conn)?;
         let conn = db.conn;
-        conn.execute("UPDATE meta SET version = ?", [std::i32::MAX])?;
+        conn.execute("UPDATE meta SET version = ?", [i32::MAX])?;
         let result = Db::open_from_conn(conn);
         assert!(result.is_err());
 
@@ -490,6 +2094,30 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn test_take_blocking() -> Result<()> {
+        let db_file = tempfile::NamedTempFile::new()?;
+        let path = db_file.path().to_str().unwrap().to_owned();
+        let mut db = Db::create(&path)?;
+
+        // nothing to take: should give up after the timeout
+        let timeout = Duration::from_millis(200);
+        let t0 = Instant::now();
+        assert_eq!(db.take_blocking("worker id", None, Some(timeout))?, None);
+        assert!(t0.elapsed() >= timeout);
+
+        // work created by another connection should wake the waiter
+        let creator = std::thread::spawn(move || -> Result<TaskId> {
+            std::thread::sleep(Duration::from_millis(200));
+            Db::open(&path)?.new_job(b"later", 1, None)
+        });
+        let job = db.take_blocking("worker id", None, Some(Duration::from_secs(30)))?;
+        let id = creator.join().unwrap()?;
+        assert_eq!(job.unwrap().id, id);
+
+        Ok(())
+    }
+
     #[test]
     fn test_logging() -> Result<()> {
         let conn = Connection::open_in_memory()?;
@@ -511,6 +2139,795 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn test_times() -> Result<()> {
+        let conn = Connection::open_in_memory()?;
+        let mut db = Db::create_from_conn(conn)?;
+
+        db.new_job(b"data", 1, None)?;
+        let t0 = Time(now());
+        db.take("worker id")?.unwrap();
+        let job = db.current_job("worker id")?.unwrap();
+        let taken = db.get_job_time(job)?.unwrap();
+        assert!(taken >= t0);
+        db.log_start(job, vec![])?;
+        let started = db.get_job_start(job)?.unwrap().time.unwrap();
+        assert!(started >= taken);
+        db.log_finish(job, 0)?;
+        assert!(db.get_job_finish(job)?.unwrap().time.unwrap() >= started);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_capture_data() -> Result<()> {
+        let conn = Connection::open_in_memory()?;
+        let mut db = Db::create_from_conn(conn)?;
+
+        db.new_job(b"data", 2, None)?;
+        db.take("worker id")?.unwrap();
+        let job = db.current_job("worker id")?.unwrap();
+        db.log_start(job, vec![])?;
+        db.log_finish(job, 0)?;
+        assert_eq!(db.get_job_finish(job)?.unwrap().data, b"");
+
+        db.take("worker id")?.unwrap();
+        let job = db.current_job("worker id")?.unwrap();
+        db.log_start(job, vec![])?;
+        db.log_finish_data(job, 0, b"output")?;
+        assert_eq!(db.get_job_finish(job)?.unwrap().data, b"output");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_usage() -> Result<()> {
+        let conn = Connection::open_in_memory()?;
+        let mut db = Db::create_from_conn(conn)?;
+
+        db.new_job(b"data", 1, None)?;
+        db.take("worker id")?.unwrap();
+        let job = db.current_job("worker id")?.unwrap();
+        db.log_start(job, vec![])?;
+        db.log_finish(job, 0)?;
+        assert_eq!(db.get_job_finish(job)?.unwrap().usage, None);
+        let usage = Usage {
+            user_time: Duration::from_millis(1500),
+            system_time: Duration::from_millis(250),
+            max_rss: 10 << 20,
+            in_blocks: 8,
+            out_blocks: 16,
+        };
+        db.set_job_usage(job, &usage)?;
+        assert_eq!(db.get_job_finish(job)?.unwrap().usage, Some(usage));
+        let stats = &db.task_stats()?[0];
+        assert_eq!(stats.cpu_time.unwrap().mean, 1.75);
+        assert_eq!(stats.max_rss.unwrap().mean, (10 << 20) as f64);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_requeue() -> Result<()> {
+        let conn = Connection::open_in_memory()?;
+        let mut db = Db::create_from_conn(conn)?;
+
+        let task = db.new_job(b"flaky", 1, None)?;
+        db.take("worker id")?.unwrap();
+        let job = db.current_job("worker id")?.unwrap();
+        db.log_start(job, vec![])?;
+        // a job that may still be running can't be requeued
+        assert!(db.requeue(job).is_err());
+        assert!(db.requeue(job + 1).is_err());
+        db.log_finish(job, 1)?;
+        assert_eq!(db.get_count(task)?, 0);
+        assert!(db.take("worker id")?.is_none());
+
+        // the repetition is available again, and the failed attempt stays in the log
+        db.requeue(job)?;
+        assert_eq!(db.get_count(task)?, 1);
+        assert!(db.is_requeued(job)?);
+        assert_eq!(db.take("worker id")?.unwrap().id, task);
+        let retry = db.current_job("worker id")?.unwrap();
+        assert!(!db.is_requeued(retry)?);
+        assert_eq!(db.get_jobs()?, vec![job, retry]);
+        assert_eq!(db.get_job_finish(job)?.unwrap().result, 1);
+        assert!(db.take("worker id")?.is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_retry_policy() -> Result<()> {
+        let conn = Connection::open_in_memory()?;
+        let mut db = Db::create_from_conn(conn)?;
+
+        let task = db.new_job(b"flaky", 1, None)?;
+        let policy = RetryPolicy {
+            max_attempts: 3,
+            delay: Duration::from_secs(0),
+            backoff: 2.0,
+        };
+        db.set_retry_policy(task, Some(policy))?;
+        assert_eq!(db.get_retry_policy(task)?, Some(policy));
+
+        // failures are retried until the attempts run out
+        let mut jobs = Vec::new();
+        for _ in 0..3 {
+            assert_eq!(db.take("worker id")?.unwrap().id, task);
+            let job = db.current_job("worker id")?.unwrap();
+            db.log_finish(job, 1)?;
+            jobs.push(job);
+        }
+        assert!(db.take("worker id")?.is_none());
+        assert!(db.is_requeued(jobs[0])? && db.is_requeued(jobs[1])?);
+        assert!(!db.is_failed(jobs[1])?);
+        assert!(!db.is_requeued(jobs[2])?);
+        assert!(db.is_failed(jobs[2])?);
+        assert_eq!(db.get_count(task)?, 0);
+
+        // a retry isn't available until its delay has elapsed
+        let task = db.new_job(b"slow to retry", 1, None)?;
+        db.set_retry_policy(
+            task,
+            Some(RetryPolicy {
+                delay: Duration::from_secs(3600),
+                ..policy
+            }),
+        )?;
+        db.take("worker id")?.unwrap();
+        let job = db.current_job("worker id")?.unwrap();
+        db.log_finish(job, 1)?;
+        assert!(db.is_requeued(job)?);
+        assert_eq!(db.get_count(task)?, 1);
+        assert!(db.job_ids_vec()?.is_empty());
+        assert!(db.take("worker id")?.is_none());
+
+        // once the delay has elapsed, it can be taken again
+        db.conn
+            .execute("UPDATE job SET retry_at = NULL WHERE id = ?", [job])?;
+        assert_eq!(db.job_ids_vec()?, vec![task]);
+        assert_eq!(db.take("worker id")?.unwrap().id, task);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_lease() -> Result<()> {
+        let conn = Connection::open_in_memory()?;
+        let mut db = Db::create_from_conn(conn)?;
+
+        let task = db.new_job(b"leased", 1, None)?;
+        db.take_leased("worker 1", Some(Duration::from_secs(3600)))?
+            .unwrap();
+        let job = db.current_job("worker 1")?.unwrap();
+        assert_eq!(db.get_lease(job)?, Some(Duration::from_secs(3600)));
+        db.heartbeat(job)?;
+        assert!(db.take("worker 2")?.is_none());
+        assert!(db.requeue(job).is_err());
+
+        // let the lease run out
+        db.conn
+            .execute("UPDATE job SET lease_expires = 0 WHERE id = ?", [job])?;
+        assert_eq!(db.get_count(task)?, 0);
+        db.reclaim_expired()?;
+        assert_eq!(db.get_count(task)?, 1);
+        assert_eq!(db.take("worker 2")?.unwrap().id, task);
+        assert!(db.is_requeued(job)?);
+        let finish = db.get_job_finish(job)?.unwrap();
+        assert_eq!(finish.result, RESULT_LEASE_EXPIRED);
+        assert!(db.heartbeat(job).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_reap() -> Result<()> {
+        let conn = Connection::open_in_memory()?;
+        let mut db = Db::create_from_conn(conn)?;
+
+        let task = db.new_job(b"reaped", 2, None)?;
+        let mut child = std::process::Command::new("sleep").arg("60").spawn()?;
+        let process = Process::from_pid(child.id())?.unwrap();
+        db.take("worker 1")?.unwrap();
+        let dead = db.current_job("worker 1")?.unwrap();
+        db.set_job_process(dead, &process)?;
+        db.take("worker 2")?.unwrap();
+        let live = db.current_job("worker 2")?.unwrap();
+        db.set_job_process(live, &Process::current()?)?;
+        assert_eq!(db.get_job_process(dead)?, Some(process.clone()));
+        assert!(process.is_alive()?);
+        assert_eq!(db.reap()?, vec![]);
+
+        child.kill()?;
+        child.wait()?;
+        assert!(!process.is_alive()?);
+        assert_eq!(db.reap()?, vec![dead]);
+        assert_eq!(db.get_job_finish(dead)?.unwrap().result, RESULT_ABANDONED);
+        assert!(db.is_requeued(dead)?);
+        assert_eq!(db.get_count(task)?, 1);
+        assert_eq!(db.reap()?, vec![]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_abandon() -> Result<()> {
+        let conn = Connection::open_in_memory()?;
+        let mut db = Db::create_from_conn(conn)?;
+
+        let task = db.new_job(b"abandoned", 2, None)?;
+        let (context, _) = db.take_and_start("worker id", None, &[])?.unwrap();
+        assert_eq!(db.get_count(task)?, 1);
+        db.abandon(context.job)?;
+        assert_eq!(
+            db.get_job_finish(context.job)?.unwrap().result,
+            RESULT_ABANDONED
+        );
+        assert_eq!(db.get_count(task)?, 2);
+        // only unfinished jobs are abandoned
+        db.abandon(context.job)?;
+        let (context, _) = db.take_and_start("worker id", None, &[])?.unwrap();
+        db.log_finish(context.job, 0)?;
+        db.abandon(context.job)?;
+        assert_eq!(db.get_job_finish(context.job)?.unwrap().result, 0);
+        assert_eq!(db.get_count(task)?, 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_stats() -> Result<()> {
+        let conn = Connection::open_in_memory()?;
+        let mut db = Db::create_from_conn(conn)?;
+
+        let task = db.new_job(b"measured", 6, None)?;
+        let idle = db.new_job(b"idle", 2, None)?;
+        let mut jobs = Vec::new();
+        for _ in 0..5 {
+            db.take("worker id")?.unwrap();
+            jobs.push(db.current_job("worker id")?.unwrap());
+        }
+        for &job in &jobs[1..] {
+            db.log_start(job, vec![])?;
+        }
+        db.log_finish(jobs[2], 0)?;
+        db.log_finish(jobs[3], 1)?;
+        db.log_finish(jobs[4], 1)?;
+        db.requeue(jobs[4])?;
+        // make the run times known
+        for (i, &job) in jobs[2..].iter().enumerate() {
+            db.conn.execute(
+                "UPDATE job_finish SET time = (SELECT time FROM job_start WHERE job = ?) + ? \
+                 WHERE job = ?",
+                params![job, i as f64 + 1.0, job],
+            )?;
+        }
+
+        let stats = db.task_stats()?;
+        assert_eq!(stats.len(), 2);
+        let s = &stats[0];
+        assert_eq!(s.task, task);
+        assert_eq!(
+            (s.queued, s.taken, s.running, s.succeeded, s.failed),
+            (2, 1, 1, 1, 1)
+        );
+        let run_time = s.run_time.unwrap();
+        assert!((run_time.mean - 2.0).abs() < 1e-6);
+        assert!((run_time.median - 2.0).abs() < 1e-6);
+        assert!((run_time.p95 - 3.0).abs() < 1e-6);
+        assert!(s.queue_wait.unwrap().p95 >= 0.0);
+        assert_eq!(stats[1].task, idle);
+        assert_eq!(stats[1].queued, 2);
+        assert_eq!(stats[1].run_time, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_queue_wait() -> Result<()> {
+        let conn = Connection::open_in_memory()?;
+        let mut db = Db::create_from_conn(conn)?;
+
+        let first = db.new_job(b"first", 1, None)?;
+        let second = db.new_job(b"second", 1, None)?;
+        db.add_dependency(second, first, Condition::Any)?;
+        db.conn.execute("UPDATE task SET time = 100", [])?;
+        db.take("worker id")?.unwrap();
+        db.log_start(1, vec![])?;
+        db.log_finish(1, 1)?;
+        db.requeue(1)?;
+        db.take("worker id")?.unwrap();
+        db.log_start(2, vec![])?;
+        db.log_finish(2, 0)?;
+        db.take("worker id")?.unwrap();
+        // the retry waited from the requeue, and the dependent job from its dependency finishing
+        for (job, taken, finished) in [(1, 110, 120), (2, 130, 140)] {
+            db.conn
+                .execute("UPDATE job SET time = ? WHERE id = ?", [taken, job])?;
+            db.conn.execute(
+                "UPDATE job_finish SET time = ? WHERE job = ?",
+                [finished, job],
+            )?;
+        }
+        db.conn
+            .execute("UPDATE job SET time = 145 WHERE id = 3", [])?;
+
+        let stats = db.task_stats()?;
+        let waits = |i: usize| {
+            let wait = stats[i].queue_wait.unwrap();
+            (wait.mean, wait.p95)
+        };
+        assert_eq!(waits(0), (10.0, 10.0));
+        assert_eq!(waits(1), (5.0, 5.0));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_eta() -> Result<()> {
+        let conn = Connection::open_in_memory()?;
+        let mut db = Db::create_from_conn(conn)?;
+
+        let later = db.new_job(b"later", 2, Some(1))?;
+        let sooner = db.new_job(b"sooner", 3, None)?;
+        // no history, no estimate
+        let eta = db.eta()?;
+        assert_eq!(eta.drained, None);
+        assert_eq!(eta.tasks.len(), 2);
+        assert_eq!(eta.tasks[0].task, sooner);
+
+        // one 10s job done, and two workers each 4s into a job
+        let t0 = now();
+        for worker in &["worker 1", "worker 2", "worker 1"] {
+            db.take(worker)?.unwrap();
+            let job = db.current_job(worker)?.unwrap();
+            db.log_start(job, vec![])?;
+            db.conn
+                .execute("UPDATE job_start SET time = time - 4 WHERE job = ?", [job])?;
+            if job == 1 {
+                db.log_finish(job, 0)?;
+                db.conn.execute(
+                    "UPDATE job_finish SET time = (SELECT time FROM job_start WHERE job = 1) + 10",
+                    [],
+                )?;
+            }
+        }
+        let eta = db.eta()?;
+        let t1 = now();
+        assert_eq!(eta.workers, 2);
+        let tasks: Vec<_> = eta.tasks.iter().map(|t| (t.task, t.remaining)).collect();
+        assert_eq!(tasks, vec![(sooner, 2), (later, 2)]);
+        // the running jobs have 6s left, which completes `sooner`;
+        // then `later`'s two 10s jobs are shared by the two workers
+        let in_range = |t: Option<Time>, secs: f64| {
+            let t = t.unwrap().0;
+            t >= t0 + secs - 1e-3 && t <= t1 + secs + 1e-3
+        };
+        assert!(in_range(eta.tasks[0].drained, 6.0));
+        assert!(in_range(eta.tasks[1].drained, 16.0));
+        assert!(in_range(eta.drained, 16.0));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_dependencies() -> Result<()> {
+        let conn = Connection::open_in_memory()?;
+        let mut db = Db::create_from_conn(conn)?;
+
+        let first = db.new_job(b"first", 2, Some(1))?;
+        let flaky = db.new_job(b"flaky", 1, Some(1))?;
+        let second = db.new_job(b"second", 1, None)?;
+        db.add_dependency(second, first, Condition::Ok)?;
+        assert!(db.add_dependency(first, first, Condition::Ok).is_err());
+        assert_eq!(db.get_dependencies(second)?, vec![(first, Condition::Ok)]);
+        let third = db.new_job(b"third", 1, None)?;
+        db.add_dependency(third, first, Condition::Ok)?;
+        db.add_dependency(third, flaky, Condition::Ok)?;
+        assert_eq!(db.job_ids_vec()?, vec![first, flaky]);
+        assert_eq!(db.blocked_ids_vec()?, vec![second, third]);
+
+        // taking all of a dependency's repetitions isn't enough; they must succeed
+        assert_eq!(db.take("worker id")?.unwrap().id, first);
+        let job = db.current_job("worker id")?.unwrap();
+        assert_eq!(db.take("worker id")?.unwrap().id, first);
+        assert_eq!(db.take("worker id")?.unwrap().id, flaky);
+        assert!(db.take("worker id")?.is_none());
+        db.log_finish(job, 0)?;
+        db.log_finish(job + 1, 0)?;
+        assert!(db.get_blockers(second)?.is_empty());
+
+        db.log_finish(job + 2, 1)?;
+        assert_eq!(
+            db.get_blockers(third)?,
+            vec![Blocker {
+                after: flaky,
+                reason: BlockReason::Failed
+            }]
+        );
+        assert_eq!(db.take("worker id")?.unwrap().id, second);
+        assert!(db.take("worker id")?.is_none());
+
+        // until the failure is retried
+        db.requeue(job + 2)?;
+        assert_eq!(db.get_blockers(third)?[0].reason, BlockReason::Unfinished);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_dependency_conditions() -> Result<()> {
+        let conn = Connection::open_in_memory()?;
+        let mut db = Db::create_from_conn(conn)?;
+
+        let ok = db.new_job(b"ok", 1, Some(1))?;
+        let notok = db.new_job(b"notok", 1, Some(1))?;
+        let mut after = |after, condition| -> Result<TaskId> {
+            let task = db.new_job(b"after", 1, None)?;
+            db.add_dependency(task, after, condition)?;
+            Ok(task)
+        };
+        let ok_ok = after(ok, Condition::Ok)?;
+        let ok_notok = after(ok, Condition::NotOk)?;
+        let ok_any = after(ok, Condition::Any)?;
+        let notok_ok = after(notok, Condition::Ok)?;
+        let notok_notok = after(notok, Condition::NotOk)?;
+        let notok_any = after(notok, Condition::Any)?;
+        assert_eq!(db.job_ids_vec()?, vec![ok, notok]);
+
+        db.take("worker id")?.unwrap();
+        db.take("worker id")?.unwrap();
+        db.log_finish(1, 0)?;
+        db.log_finish(2, 256 + 9)?;
+        assert_eq!(
+            db.job_ids_vec()?,
+            vec![ok_ok, ok_any, notok_notok, notok_any]
+        );
+        assert_eq!(db.get_blockers(ok_notok)?[0].reason, BlockReason::Succeeded);
+        assert_eq!(db.get_blockers(notok_ok)?[0].reason, BlockReason::Failed);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_job_context() -> Result<()> {
+        let conn = Connection::open_in_memory()?;
+        let mut db = Db::create_from_conn(conn)?;
+
+        let task = db.new_job(b"in.txt", 3, None)?;
+        db.take("w0")?.unwrap();
+        db.take("w1")?.unwrap();
+        // a requeued repetition keeps its number
+        db.log_finish(1, 1)?;
+        db.requeue(1)?;
+        db.take("w2")?.unwrap();
+        db.take("w3")?.unwrap();
+        let reps: Vec<_> = (1..=4).map(|job| db.get_repetition(job).unwrap()).collect();
+        assert_eq!(reps, vec![0, 1, 0, 2]);
+
+        let context = db.get_job_context(4)?;
+        assert_eq!(
+            context,
+            JobContext {
+                job: 4,
+                task,
+                repetition: 2,
+                worker: "w3".to_owned(),
+                data: b"in.txt".to_vec(),
+            }
+        );
+        assert!(has_placeholders(b"--out={job}"));
+        assert!(!has_placeholders(b"{}"));
+        assert_eq!(
+            context.expand(b"{data}:{task}/{rep}.{job}@{worker} {}{other}{"),
+            b"in.txt:1/2.4@w3 {}{other}{".to_vec()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_take_and_start() -> Result<()> {
+        let conn = Connection::open_in_memory()?;
+        let mut db = Db::create_from_conn(conn)?;
+
+        let cmd = vec![b"process".to_vec(), b"{data}".to_vec()];
+        assert!(db.take_and_start("worker id", None, &cmd)?.is_none());
+        db.new_job(b"in.txt", 1, None)?;
+        let (context, cmd) = db.take_and_start("worker id", None, &cmd)?.unwrap();
+        assert_eq!(context.job, 1);
+        assert_eq!(cmd, vec![b"process".to_vec(), b"in.txt".to_vec()]);
+        // started as soon as it's taken
+        assert_eq!(db.get_started_jobs()?, vec![1]);
+        assert_eq!(
+            db.get_job_start(1)?.unwrap().cmd.to_string(),
+            "\"process\" \"in.txt\""
+        );
+        assert!(db
+            .take_and_start_blocking("worker id", None, Some(Duration::from_millis(10)), &cmd)?
+            .is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_limits() -> Result<()> {
+        let conn = Connection::open_in_memory()?;
+        let mut db = Db::create_from_conn(conn)?;
+
+        let task = db.new_job(b"", 1, None)?;
+        assert_eq!(db.get_limits(task)?, Limits::default());
+        let limits = Limits {
+            timeout: Some(Duration::from_secs(60)),
+            max_memory: Some(1 << 30),
+            max_cpu_time: None,
+            nice: Some(10),
+        };
+        db.set_limits(task, &limits)?;
+        assert_eq!(db.get_limits(task)?, limits);
+        let mine = Limits {
+            max_cpu_time: Some(Duration::from_secs(5)),
+            nice: Some(0),
+            ..Limits::default()
+        };
+        assert_eq!(
+            mine.or(limits),
+            Limits {
+                timeout: Some(Duration::from_secs(60)),
+                max_memory: Some(1 << 30),
+                max_cpu_time: Some(Duration::from_secs(5)),
+                nice: Some(0),
+            }
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_cancel() -> Result<()> {
+        let conn = Connection::open_in_memory()?;
+        let mut db = Db::create_from_conn(conn)?;
+
+        let task = db.new_job(b"runaway", 1, None)?;
+        db.set_retry_policy(
+            task,
+            Some(RetryPolicy {
+                max_attempts: 3,
+                delay: Duration::ZERO,
+                backoff: 1.0,
+            }),
+        )?;
+        db.take("worker id")?.unwrap();
+        assert_eq!(db.get_cancel(1)?, None);
+        db.cancel(1, 15)?;
+        assert_eq!(db.get_cancel(1)?, Some(15));
+        // cancelled jobs aren't retried
+        db.log_finish(1, RESULT_CANCELLED)?;
+        assert!(db.take("worker id")?.is_none());
+        assert!(db.cancel(1, 15).is_err());
+        assert!(db.cancel(2, 15).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_suspend() -> Result<()> {
+        let conn = Connection::open_in_memory()?;
+        let mut db = Db::create_from_conn(conn)?;
+
+        db.new_job(b"slow", 1, None)?;
+        db.take("worker id")?.unwrap();
+        db.log_start(1, vec![])?;
+        assert!(!db.is_suspended(1)?);
+        db.suspend(1, true)?;
+        assert!(db.is_suspended(1)?);
+        db.log_suspended(1, true)?;
+        db.conn
+            .execute("UPDATE job_suspend SET start = start - 5", [])?;
+        db.suspend(1, false)?;
+        assert!(!db.is_suspended(1)?);
+        db.log_suspended(1, false)?;
+        let paused = db.get_paused_time(1)?.as_secs_f64();
+        assert!((paused - 5.0).abs() < 0.5);
+        db.conn
+            .execute("UPDATE job_start SET time = time - 7", [])?;
+        db.log_finish(1, 0)?;
+        assert!(db.suspend(1, true).is_err());
+        // the time spent suspended isn't run time
+        let run_time = db.task_stats()?[0].run_time.unwrap();
+        assert!((run_time.mean - 2.0).abs() < 0.5);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_preempt() -> Result<()> {
+        let conn = Connection::open_in_memory()?;
+        let mut db = Db::create_from_conn(conn)?;
+
+        let background = db.new_job(b"background", 2, Some(10))?;
+        db.set_preemptible(background, true)?;
+        let important = db.new_job(b"important", 1, Some(5))?;
+        // the important job is taken first, and isn't preemptible
+        assert_eq!(db.take("a")?.unwrap().id, important);
+        assert_eq!(db.take("b")?.unwrap().id, background);
+        assert_eq!(db.take("c")?.unwrap().id, background);
+        for job in 1..=3 {
+            db.log_start(job, vec![])?;
+        }
+
+        let urgent = db.new_job(b"urgent", 1, Some(0))?;
+        assert_eq!(db.preempt(urgent, 15, Duration::ZERO)?, vec![3]);
+        assert!(db.is_preempted(3)?);
+        assert_eq!(db.get_cancel(3)?, Some(15));
+        // job 3 is already being preempted
+        assert_eq!(db.preempt(urgent, 15, Duration::ZERO)?, vec![2]);
+
+        // nothing is preempted for a task that can't run yet
+        let blocked = db.new_job(b"blocked", 1, Some(0))?;
+        db.add_dependency(blocked, important, Condition::Ok)?;
+        assert_eq!(db.preempt(blocked, 15, Duration::ZERO)?, vec![]);
+        db.add_count(blocked, -1)?;
+
+        db.log_finish(3, RESULT_PREEMPTED)?;
+        assert!(db.is_requeued(3)?);
+        assert_eq!(db.take("d")?.unwrap().data, b"urgent");
+        assert_eq!(db.take("e")?.unwrap().data, b"background");
+        assert!(db.take("f")?.is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_multiple_jobs() -> Result<()> {
+        let conn = Connection::open_in_memory()?;
+        let mut db = Db::create_from_conn(conn)?;
+
+        db.new_job(b"first", 1, None)?;
+        db.new_job(b"second", 1, None)?;
+        let (job, first) = db.take_with_id("worker id", None)?.unwrap();
+        assert_eq!(job.data, b"first");
+        let (job, second) = db.take_with_id("worker id", None)?.unwrap();
+        assert_eq!(job.data, b"second");
+        assert_eq!(db.current_job("worker id")?, Some(second));
+        assert!(db.is_worker_job("worker id", first)?);
+        assert!(!db.is_worker_job("other worker", first)?);
+        assert!(!db.is_worker_job("worker id", 3)?);
+
+        // the older job can still be logged
+        db.log_start(first, vec![])?;
+        db.log_start(second, vec![])?;
+        db.log_finish(first, 1)?;
+        db.log_finish(second, 0)?;
+        assert_eq!(db.get_job_finish(first)?.unwrap().result, 1);
+        assert_eq!(db.get_job_finish(second)?.unwrap().result, 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_preempt_retry() -> Result<()> {
+        let conn = Connection::open_in_memory()?;
+        let mut db = Db::create_from_conn(conn)?;
+
+        let background = db.new_job(b"background", 1, Some(10))?;
+        db.set_preemptible(background, true)?;
+        db.set_retry_policy(
+            background,
+            Some(RetryPolicy {
+                max_attempts: 2,
+                delay: Duration::ZERO,
+                backoff: 1.0,
+            }),
+        )?;
+        db.take("worker id")?.unwrap();
+        db.log_start(1, vec![])?;
+        let urgent = db.new_job(b"urgent", 1, Some(0))?;
+        assert_eq!(db.preempt(urgent, 15, Duration::ZERO)?, vec![1]);
+        db.log_finish(1, RESULT_PREEMPTED)?;
+        assert_eq!(db.take("worker id")?.unwrap().id, urgent);
+
+        // the preemption didn't count as an attempt, so a failure is still retried
+        assert_eq!(db.take("worker id")?.unwrap().id, background);
+        db.log_start(3, vec![])?;
+        db.log_finish(3, 1)?;
+        assert!(!db.is_failed(3)?);
+        assert_eq!(db.take("worker id")?.unwrap().id, background);
+        db.log_start(4, vec![])?;
+        db.log_finish(4, 1)?;
+        assert!(db.is_failed(4)?);
+
+        // a job that fails on its own before its preemption takes effect used up an attempt
+        db.add_count(background, 1)?;
+        db.take("worker id")?.unwrap();
+        db.log_start(5, vec![])?;
+        db.add_count(urgent, 1)?;
+        assert_eq!(db.preempt(urgent, 15, Duration::ZERO)?, vec![5]);
+        db.log_finish(5, 1)?;
+        assert_eq!(db.take("worker id")?.unwrap().id, urgent);
+        assert_eq!(db.take("worker id")?.unwrap().id, background);
+        db.log_start(7, vec![])?;
+        db.log_finish(7, 1)?;
+        assert!(db.is_failed(7)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_started_jobs() -> Result<()> {
+        let conn = Connection::open_in_memory()?;
+        let mut db = Db::create_from_conn(conn)?;
+
+        db.new_job(b"data", 4, None)?;
+        for worker in ["a", "a", "b", "b"] {
+            db.take(worker)?.unwrap();
+        }
+        for job in 1..=3 {
+            db.log_start(job, vec![])?;
+        }
+        // both of worker a's jobs are running
+        assert_eq!(db.get_started_jobs()?, vec![1, 2, 3]);
+        db.log_finish(2, 0)?;
+        assert_eq!(db.get_started_jobs()?, vec![1, 3]);
+        assert_eq!(db.eta()?.workers, 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_upgrade_v2() -> Result<()> {
+        let conn = Connection::open_in_memory()?;
+        conn.execute("CREATE TABLE meta (version INTEGER)", [])?;
+        conn.execute("CREATE TABLE task (id INTEGER PRIMARY KEY, count INTEGER NOT NULL, data BLOB NOT NULL, priority INTEGER)", [])?;
+        // as upgraded from version 1, i.e. without job.time
+        conn.execute(
+            "CREATE TABLE job (id INTEGER PRIMARY KEY, task REFERENCES task, worker TEXT NOT NULL)",
+            [],
+        )?;
+        conn.execute(
+            "CREATE TABLE job_start (job PRIMARY KEY REFERENCES job, time INTEGER, cmd BLOB)",
+            [],
+        )?;
+        conn.execute("CREATE TABLE job_finish (job PRIMARY KEY REFERENCES job, result INTEGER, time INTEGER, data BLOB)", [])?;
+        conn.execute("INSERT INTO meta VALUES (2)", [])?;
+        conn.execute(
+            "INSERT INTO task (id, count, data) VALUES (1, 2, x'00')",
+            [],
+        )?;
+        conn.execute("INSERT INTO job (id, task, worker) VALUES (1, 1, 'w')", [])?;
+        conn.execute(
+            "INSERT INTO job_start (job, time, cmd) VALUES (1, '2021-11-03', NULL)",
+            [],
+        )?;
+        conn.execute(
+            "INSERT INTO job_finish (job, result, time) VALUES (1, 0, 1635984000)",
+            [],
+        )?;
+
+        let mut db = Db::open_from_conn(conn)?;
+        assert_eq!(get_version(&db.conn)?, DB_VERSION);
+        // a day is too coarse to time anything with
+        let start: Option<f64> =
+            db.conn
+                .query_row("SELECT time FROM job_start WHERE job = 1", [], |row| {
+                    row.get(0)
+                })?;
+        assert_eq!(start, None);
+        assert_eq!(
+            db.get_job_finish(1)?.unwrap().time,
+            Some(Time(1635984000.0))
+        );
+        assert_eq!(db.task_stats()?[0].run_time, None);
+        assert_eq!(db.get_job_time(1)?, None);
+        assert_eq!(db.get_count(1)?, 1);
+        assert!(!db.is_requeued(1)?);
+        db.requeue(1)?;
+        assert_eq!(db.get_count(1)?, 2);
+
+        Ok(())
+    }
+
     #[test]
     fn test_priority() -> Result<()> {
         let conn = Connection::open_in_memory()?;
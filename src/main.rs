@@ -1,11 +1,121 @@
-use clap::{crate_version, App, AppSettings, Arg, SubCommand};
-use jerbs::{Command, Db, Time};
-use std::collections::HashMap;
+use clap::{crate_version, App, AppSettings, Arg, ArgMatches, SubCommand};
+use jerbs::{Command, Condition, Db, JobId, Limits, Process, RetryPolicy, Time};
 use std::fmt::{self, Display};
 use std::io::{self, Read, Write};
-use std::os::unix::ffi::OsStringExt;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::time::Duration;
 use tabled::{Style, Table, Tabled};
 
+mod monitor;
+mod pool;
+
+use monitor::Monitor;
+use pool::Pool;
+
+/// How long `create --preempt` gives waiting workers to take the new job before preempting any.
+const PREEMPT_WAIT: Duration = Duration::from_secs(2);
+
+/// Parse a duration like timeout(1) does: a number of seconds, optionally with a suffix of s, m,
+/// h or d for seconds, minutes, hours or days.
+fn parse_duration(x: &str) -> Duration {
+    let (n, unit) = match x.char_indices().last() {
+        Some((i, 's')) => (&x[..i], 1.0),
+        Some((i, 'm')) => (&x[..i], 60.0),
+        Some((i, 'h')) => (&x[..i], 60.0 * 60.0),
+        Some((i, 'd')) => (&x[..i], 24.0 * 60.0 * 60.0),
+        _ => (x, 1.0),
+    };
+    let n: f64 = n
+        .parse()
+        .unwrap_or_else(|_| panic!("invalid duration: {:?}", x));
+    Duration::from_secs_f64(n * unit)
+}
+
+/// Parse a number of bytes, optionally with a suffix of K, M, G or T (powers of 1024).
+fn parse_size(x: &str) -> u64 {
+    let (n, unit) = match x.char_indices().last() {
+        Some((i, 'K')) => (&x[..i], 1 << 10),
+        Some((i, 'M')) => (&x[..i], 1 << 20),
+        Some((i, 'G')) => (&x[..i], 1 << 30),
+        Some((i, 'T')) => (&x[..i], 1 << 40),
+        _ => (x, 1),
+    };
+    let n: u64 = n
+        .parse()
+        .unwrap_or_else(|_| panic!("invalid size: {:?}", x));
+    n * unit
+}
+
+/// The limits given by the arguments from [`limit_args`].
+fn parse_limits(args: &ArgMatches) -> Limits {
+    Limits {
+        timeout: args.value_of("timeout").map(parse_duration),
+        max_memory: args.value_of("max-memory").map(parse_size),
+        max_cpu_time: args.value_of("max-cpu-time").map(parse_duration),
+        nice: args
+            .value_of("nice")
+            .map(|x| x.parse().expect("nice must be an integer")),
+    }
+}
+
+/// Write a taken job to standard output, in the format selected by `take`'s arguments.
+fn print_job(db: &Db, args: &ArgMatches, id: JobId, data: &[u8]) -> jerbs::Result<()> {
+    let mut out = io::stdout();
+    if let Some(template) = args.value_of_os("template") {
+        let context = db.get_job_context(id)?;
+        out.write_all(&context.expand(template.as_bytes()))?;
+        return Ok(());
+    }
+    match args.value_of("format") {
+        Some("nul") => {
+            let task = db.get_job_context(id)?.task;
+            write!(out, "{}\0{}\0", id, task)?;
+            out.write_all(data)?;
+        }
+        Some("json") => {
+            let context = db.get_job_context(id)?;
+            let json = serde_json::json!({
+                "job": context.job,
+                "task": context.task,
+                "rep": context.repetition,
+                "worker": context.worker,
+                "data": base64::encode(&context.data),
+            });
+            writeln!(out, "{}", json)?;
+        }
+        _ => {
+            if args.is_present("print-job-id") {
+                writeln!(out, "{}", id)?;
+            }
+            out.write_all(data)?;
+        }
+    }
+    Ok(())
+}
+
+/// Parse a signal name (with or without the SIG prefix) or number.
+fn parse_signal(x: &str) -> i32 {
+    const SIGNALS: &[(&str, i32)] = &[
+        ("HUP", libc::SIGHUP),
+        ("INT", libc::SIGINT),
+        ("QUIT", libc::SIGQUIT),
+        ("KILL", libc::SIGKILL),
+        ("USR1", libc::SIGUSR1),
+        ("USR2", libc::SIGUSR2),
+        ("TERM", libc::SIGTERM),
+    ];
+    if let Ok(n) = x.parse() {
+        return n;
+    }
+    let name = x.to_uppercase();
+    let name = name.strip_prefix("SIG").unwrap_or(&name);
+    SIGNALS
+        .iter()
+        .find(|(s, _)| *s == name)
+        .unwrap_or_else(|| panic!("unknown signal: {:?}", x))
+        .1
+}
+
 fn read_data() -> Vec<u8> {
     let mut buf = Vec::new();
     io::stdin().read_to_end(&mut buf).unwrap();
@@ -19,6 +129,88 @@ enum BuildingHelp {
     Short,
 }
 
+/// Limits on a job's command, which can be set for a job or when monitoring it.
+fn limit_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("timeout")
+            .help("terminate the command if it runs longer than this (e.g. 90, 30s, 5m, 2h, 1d)")
+            .long("timeout")
+            .takes_value(true),
+        Arg::with_name("max-memory")
+            .help("limit the command's virtual memory to this many bytes (e.g. 4096, 512M, 2G)")
+            .long("max-memory")
+            .takes_value(true),
+        Arg::with_name("max-cpu-time")
+            .help("limit the command's CPU time (e.g. 90, 30s, 5m, 2h, 1d)")
+            .long("max-cpu-time")
+            .takes_value(true),
+        Arg::with_name("nice")
+            .help("run the command with this niceness")
+            .long("nice")
+            .takes_value(true)
+            .allow_hyphen_values(true),
+    ]
+}
+
+/// Option selecting which of a worker's jobs a command applies to.
+fn job_arg() -> Arg<'static, 'static> {
+    Arg::with_name("job")
+        .help("the id of the job, if not the worker's latest (see take --print-job-id)")
+        .long("job")
+        .takes_value(true)
+}
+
+/// The job given with --job, or else the worker's latest job.
+fn worker_job(db: &mut Db, args: &ArgMatches, worker: &str) -> jerbs::Result<JobId> {
+    Ok(match args.value_of("job") {
+        Some(job) => {
+            let job = job.parse().expect("job ids are integers");
+            assert!(
+                db.is_worker_job(worker, job)?,
+                "job {} wasn't taken by worker {}",
+                job,
+                worker
+            );
+            job
+        }
+        None => db
+            .current_job(worker)?
+            .expect("worker currently has no job"),
+    })
+}
+
+/// Options for how a job's command is run, shared by monitor and run.
+fn monitor_args() -> Vec<Arg<'static, 'static>> {
+    let mut args = limit_args();
+    args.extend(vec![
+        Arg::with_name("requeue")
+            .short("r")
+            .long("requeue-on-fail")
+            .help("If the command executes with non-zero status, put its job back in the queue"),
+        Arg::with_name("track-pid")
+            .help("record monitor's process, so the job can be reaped if it dies")
+            .long("track-pid"),
+        Arg::with_name("capture")
+            .help("log the end of the command's output as the job's result data")
+            .long("capture")
+            .takes_value(true)
+            .possible_values(&["stdout", "stderr", "both"]),
+        Arg::with_name("capture-limit")
+            .help("maximum number of bytes of output to log (default = 65536)")
+            .long("capture-limit")
+            .takes_value(true)
+            .requires("capture"),
+        Arg::with_name("stdin-data")
+            .help("write the job's data to the command's standard input")
+            .long("stdin-data"),
+        Arg::with_name("kill-after")
+            .help("kill the command if it's still running this long after being terminated (default = 10s)")
+            .long("kill-after")
+            .takes_value(true),
+    ]);
+    args
+}
+
 fn build_app(help: BuildingHelp) -> App<'static, 'static> {
     let mut app = App::new("jerbs")
         .version(crate_version!())
@@ -71,6 +263,62 @@ fn build_app(help: BuildingHelp) -> App<'static, 'static> {
                     .short("p")
                     .long("priority")
                     .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("max-attempts")
+                    .help("retry failed repetitions, up to this many attempts in total")
+                    .long("max-attempts")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("retry-delay")
+                    .help("how long to wait before the first retry (e.g. 90, 30s, 5m; default = 0)")
+                    .long("retry-delay")
+                    .takes_value(true)
+                    .requires("max-attempts"),
+            )
+            .arg(
+                Arg::with_name("backoff")
+                    .help("factor by which the retry delay grows for each retry (default = 2)")
+                    .long("backoff")
+                    .takes_value(true)
+                    .requires("max-attempts"),
+            )
+            .args(&limit_args())
+            .arg(
+                Arg::with_name("preemptible")
+                    .help("allow running repetitions to be preempted by more urgent jobs")
+                    .long("preemptible"),
+            )
+            .arg(
+                Arg::with_name("preempt")
+                    .help("preempt running preemptible jobs with a higher priority number to make room, if no waiting worker takes the job within 2s")
+                    .long("preempt"),
+            )
+            .arg(
+                Arg::with_name("after-ok")
+                    .help("wait until all repetitions of the given job have succeeded (repeatable)")
+                    .long("after-ok")
+                    .alias("after")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1),
+            )
+            .arg(
+                Arg::with_name("after-notok")
+                    .help("wait until the given job has finished with some repetitions failed (repeatable)")
+                    .long("after-notok")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1),
+            )
+            .arg(
+                Arg::with_name("after-any")
+                    .help("wait until all repetitions of the given job have finished (repeatable)")
+                    .long("after-any")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1),
             ),
         SubCommand::with_name("list-available")
             .about("list jobs available to be taken")
@@ -88,6 +336,49 @@ fn build_app(help: BuildingHelp) -> App<'static, 'static> {
                     .short("w")
                     .long("wait"),
             )
+            .arg(
+                Arg::with_name("timeout")
+                    .help("with --wait, give up after this long (e.g. 90, 30s, 5m)")
+                    .short("t")
+                    .long("timeout")
+                    .takes_value(true)
+                    .requires("wait"),
+            )
+            .arg(
+                Arg::with_name("lease")
+                    .help("requeue the job unless a heartbeat is received this often (e.g. 90, 30s, 5m)")
+                    .short("l")
+                    .long("lease")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("track-pid")
+                    .help("record the worker's process, so the job can be reaped if it dies")
+                    .long("track-pid"),
+            )
+            .arg(
+                Arg::with_name("print-job-id")
+                    .help("print the id of the job taken on a line before its data (for --job)")
+                    .long("print-job-id"),
+            )
+            .arg(
+                Arg::with_name("format")
+                    .help(
+                        "how to print the job taken: raw (its data), nul (job id, task id and \
+                         data, separated by NULs) or json (with base64 data)",
+                    )
+                    .long("format")
+                    .takes_value(true)
+                    .possible_values(&["raw", "nul", "json"])
+                    .conflicts_with("print-job-id"),
+            )
+            .arg(
+                Arg::with_name("template")
+                    .help("print the job taken by replacing placeholders in this (see monitor --help)")
+                    .long("template")
+                    .takes_value(true)
+                    .conflicts_with_all(&["format", "print-job-id"]),
+            )
             .arg(
                 Arg::with_name("worker-id")
                     .help("any string identifying the worker taking the job")
@@ -110,14 +401,97 @@ fn build_app(help: BuildingHelp) -> App<'static, 'static> {
                     .short("v")
                     .long("verbose"),
             ),
+        SubCommand::with_name("stats")
+            .about("summarize the state and timing of each job's repetitions")
+            .long_about(
+                "Summarize the state and timing of each job's repetitions.\n\n\
+                 Without --verbose, prints a tab-separated line for each job with these fields:\n\
+                 id, queued, taken, running, succeeded, failed, \
+                 run time mean, median, 95th percentile, \
+                 queue wait mean, median, 95th percentile, \
+                 CPU time (user + system) mean, median, 95th percentile, \
+                 max RSS mean, median, 95th percentile.\n\
+                 Times are in seconds, and sizes in bytes; they are empty if there are no samples. \
+                 Resource usage is only known for jobs run by monitor.",
+            )
+            .arg(
+                Arg::with_name("verbose")
+                    .help("informative output for interactive use")
+                    .short("v")
+                    .long("verbose"),
+            ),
+        SubCommand::with_name("eta")
+            .about("estimate when each job's remaining repetitions will be done")
+            .long_about(
+                "Estimate when each job's remaining repetitions will be done, based on past run \
+                 times and the number of jobs running now.\n\n\
+                 Without --verbose, prints a tab-separated line for each job with repetitions \
+                 remaining: id, number remaining, and estimated unix time of completion (empty if \
+                 it can't be estimated); then a line for the whole queue, with the id \"all\".",
+            )
+            .arg(
+                Arg::with_name("verbose")
+                    .help("informative output for interactive use")
+                    .short("v")
+                    .long("verbose"),
+            ),
         SubCommand::with_name("monitor")
             .about("run a command, invoking log-start and log-finish appropriately")
+            .long_about(
+                "Run a command, invoking log-start and log-finish appropriately.\n\n\
+                 In the command's arguments, these placeholders are replaced with the job's \
+                 values: {data}, {task} (job id), {job} (the id of this run of it), \
+                 {rep} (which repetition it is, from 0), {worker}.\n\n\
+                 The same values, and the database's path, are exported to the command's \
+                 environment as JERBS_TASK_ID, JERBS_JOB_ID, JERBS_REPETITION, JERBS_WORKER and \
+                 JERBS_DB.\n\n\
+                 The command is run in its own process group; if monitor is in the foreground \
+                 of a terminal, that group is while the command runs. Signals that would end monitor \
+                 (SIGHUP, SIGINT, SIGQUIT, SIGTERM) are passed on to it instead, and its result \
+                 logged as usual; while no command is running (e.g. with --wait), they make \
+                 monitor exit with status 128 + SIGNAL (logging any job it took as abandoned). If monitor is killed anyway, so is the command (on Linux); use \
+                 --track-pid so that reap can log the job as abandoned.\n\n\
+                 With --timeout, the command's process group is sent SIGTERM when the time is up (and SIGKILL if it's still running after \
+                 --kill-after); the job is logged with result 515, and monitor exits with status \
+                 124. Limits that aren't given are taken from the job's settings, if it has \
+                 them (see create --help).\n\n\
+                 The suspend and resume commands stop and continue the command's process group; \
+                 time spent suspended isn't counted in the job's run time, or against its timeout. \
+                 If the job is cancelled with the kill command, its command is sent the requested \
+                 signal, and the job is logged with result 516 (and isn't retried). If it's \
+                 preempted by a job created with --preempt, it's logged with result 517 and its \
+                 repetition is requeued.\n\n\
+                 With --take, exits with status 2 if there is no job to take.",
+            )
+            .args(&monitor_args())
+            .arg(
+                Arg::with_name("take")
+                    .help("take a job first, and log its start in the same transaction")
+                    .long("take"),
+            )
             .arg(
-                Arg::with_name("requeue")
-                    .short("r")
-                    .long("requeue-on-fail")
-                    .help("If the command executes with non-zero status, put its job back in the queue"),
+                Arg::with_name("wait")
+                    .help("with --take, wait for a job to become available")
+                    .short("w")
+                    .long("wait")
+                    .requires("take"),
             )
+            .arg(
+                Arg::with_name("wait-timeout")
+                    .help("with --wait, give up after this long (e.g. 90, 30s, 5m)")
+                    .long("wait-timeout")
+                    .takes_value(true)
+                    .requires("wait"),
+            )
+            .arg(
+                Arg::with_name("lease")
+                    .help("with --take, requeue the job unless a heartbeat is received this often (e.g. 90, 30s, 5m)")
+                    .short("l")
+                    .long("lease")
+                    .takes_value(true)
+                    .requires("take"),
+            )
+            .arg(job_arg().conflicts_with("take"))
             .arg(
                 Arg::with_name("worker-id")
                     .help("any string identifying the worker taking the job")
@@ -131,6 +505,55 @@ fn build_app(help: BuildingHelp) -> App<'static, 'static> {
                     .multiple(true)
                     .last(true),
             ),
+        SubCommand::with_name("run")
+            .about("run a pool of workers, monitoring a command for each job taken")
+            .long_about(
+                "Run a pool of workers, each repeatedly taking a job and monitoring the command \
+                 for it. Placeholders in the command are replaced as for monitor; if there are \
+                 none (and --stdin-data isn't given), the job's data is appended as the last \
+                 argument.\n\n\
+                 Stops when the queue is drained, unless --wait is given. On the first interrupt, \
+                 stops taking jobs and lets running ones finish; on the next, signals them too. \
+                 Exits with status 1 if any job failed or the pool was interrupted.",
+            )
+            .args(&monitor_args())
+            .arg(
+                Arg::with_name("jobs")
+                    .help("number of jobs to run at once (default = 1)")
+                    .short("j")
+                    .long("jobs")
+                    .takes_value(true)
+                    .validator(|x| match x.parse::<usize>() {
+                        Ok(n) if n > 0 => Ok(()),
+                        _ => Err("jobs must be a positive integer".into()),
+                    }),
+            )
+            .arg(
+                Arg::with_name("wait")
+                    .help("when the queue is empty, wait for more jobs instead of stopping")
+                    .short("w")
+                    .long("wait"),
+            )
+            .arg(
+                Arg::with_name("lease")
+                    .help("requeue a job unless a heartbeat is received this often (e.g. 90, 30s, 5m)")
+                    .short("l")
+                    .long("lease")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("worker-id")
+                    .help("prefix of the workers' ids, which end in .SLOT (default = HOST:PID)")
+                    .long("worker-id")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("command")
+                    .help("command to run")
+                    .required(true)
+                    .multiple(true)
+                    .last(true),
+            ),
         SubCommand::with_name("modify")
             .about("alter an existing job")
             .arg(
@@ -152,9 +575,57 @@ fn build_app(help: BuildingHelp) -> App<'static, 'static> {
                     .short("p")
                     .long("priority")
                     .takes_value(true),
+            )
+            .args(&limit_args())
+            .arg(
+                Arg::with_name("clear-limits")
+                    .help("remove the job's limits, other than any given with this")
+                    .long("clear-limits"),
             ),
     ];
     let uncommon_subcommands = vec![
+        SubCommand::with_name("suspend")
+            .about("stop a running job's command until it's resumed (see monitor)")
+            .arg(
+                Arg::with_name("job-id")
+                    .help("the id of the running job, as shown by list-running")
+                    .required(true)
+                    .index(1),
+            ),
+        SubCommand::with_name("resume")
+            .about("continue a suspended job's command")
+            .arg(
+                Arg::with_name("job-id")
+                    .help("the id of the suspended job")
+                    .required(true)
+                    .index(1),
+            ),
+        SubCommand::with_name("kill")
+            .about("cancel a running job, signalling its command (see monitor)")
+            .arg(
+                Arg::with_name("job-id")
+                    .help("the id of the running job, as shown by list-running")
+                    .required(true)
+                    .index(1),
+            )
+            .arg(
+                Arg::with_name("signal")
+                    .help("the signal to send, by name or number (default = TERM)")
+                    .short("s")
+                    .long("signal")
+                    .takes_value(true),
+            ),
+        SubCommand::with_name("reap")
+            .about("requeue jobs whose processes on this host died (see --track-pid)"),
+        SubCommand::with_name("heartbeat")
+            .about("renew the lease on a worker's current job")
+            .arg(job_arg())
+            .arg(
+                Arg::with_name("worker-id")
+                    .help("any string identifying the worker taking the job")
+                    .required(true)
+                    .index(1),
+            ),
         SubCommand::with_name("get-data")
             .about("get the data associated with a job")
             .arg(Arg::with_name("job-id").required(true).index(1)),
@@ -163,6 +634,7 @@ fn build_app(help: BuildingHelp) -> App<'static, 'static> {
             .arg(Arg::with_name("job-id").required(true).index(1)),
         SubCommand::with_name("log-start")
             .about("add a start event to the log")
+            .arg(job_arg())
             .arg(
                 Arg::with_name("worker-id")
                     .help("any string identifying the worker taking the job")
@@ -177,6 +649,7 @@ fn build_app(help: BuildingHelp) -> App<'static, 'static> {
             ),
         SubCommand::with_name("log-finish")
             .about("add a finish event to the log")
+            .arg(job_arg())
             .arg(
                 Arg::with_name("worker-id")
                     .help("any string identifying the worker taking the job")
@@ -188,6 +661,11 @@ fn build_app(help: BuildingHelp) -> App<'static, 'static> {
                     .help("integer result code (0 = no error)")
                     .required(true)
                     .index(2),
+            )
+            .arg(
+                Arg::with_name("read-data")
+                    .help("read result data to log from standard input")
+                    .long("read-data"),
             ),
     ];
     app = app.subcommands(common_subcommands);
@@ -205,14 +683,30 @@ struct Task {
     data: String,
 }
 
+#[derive(Tabled)]
+struct BlockedTask {
+    id: u32,
+    count: u64,
+    priority: i32,
+    blocked: String,
+    data: String,
+}
+
 #[derive(Tabled)]
 struct JobStatus {
     worker: String,
+    take_time: Paw<Time>,
     start_time: Paw<Time>,
     start_cmd: Paw<Command>,
     finish_result: Paw<i32>,
     finish_time: Paw<Time>,
     finish_data: Paw<MaybeUtf8>,
+    user_time: Paw<Secs>,
+    sys_time: Paw<Secs>,
+    max_rss: Paw<Bytes>,
+    blocks_in: Paw<u64>,
+    blocks_out: Paw<u64>,
+    state: &'static str,
 }
 
 enum Paw<T> {
@@ -244,57 +738,238 @@ impl Display for MaybeUtf8 {
 
 fn print_statuses(jobs: impl IntoIterator<Item = jerbs::JobId>, db: &Db) -> jerbs::Result<()> {
     let mut entries = Vec::new();
-    let mut worker_latest = HashMap::new();
     for job in jobs.into_iter() {
         let worker = db.get_job_worker(job)?;
-        let latest = match worker_latest.get(&worker) {
-            Some(latest) => *latest,
-            None => {
-                let latest = db.get_worker_latest_job(&worker)?.unwrap();
-                worker_latest.insert(worker.to_string(), latest);
-                latest
-            }
-        };
-        let is_latest = job == latest;
+        let take_time = db.get_job_time(job)?.map(Paw::Present).unwrap_or(Paw::What);
         let start = db.get_job_start(job)?;
         let finish = db.get_job_finish(job)?;
         let start_time = start
             .as_ref()
-            .map(|x| Paw::Present(x.time))
-            .unwrap_or(if is_latest { Paw::Absent } else { Paw::What });
-        let start_cmd = start.map(|x| Paw::Present(x.cmd)).unwrap_or(if is_latest {
-            Paw::Absent
-        } else {
-            Paw::What
-        });
+            .map(|x| x.time.map(Paw::Present).unwrap_or(Paw::What))
+            .unwrap_or(Paw::Absent);
+        let start_cmd = start.map(|x| Paw::Present(x.cmd)).unwrap_or(Paw::Absent);
         let finish_result = finish
             .as_ref()
             .map(|x| Paw::Present(x.result))
-            .unwrap_or(if is_latest { Paw::Absent } else { Paw::What });
+            .unwrap_or(Paw::Absent);
         let finish_time = finish
             .as_ref()
-            .map(|x| Paw::Present(x.time))
-            .unwrap_or(if is_latest { Paw::Absent } else { Paw::What });
+            .map(|x| x.time.map(Paw::Present).unwrap_or(Paw::What))
+            .unwrap_or(Paw::Absent);
+        let usage = finish.as_ref().and_then(|x| x.usage);
         let finish_data = finish
             .map(|x| Paw::Present(MaybeUtf8(x.data)))
-            .unwrap_or(if is_latest { Paw::Absent } else { Paw::What });
+            .unwrap_or(Paw::Absent);
+        let state = if db.is_requeued(job)? {
+            "requeued"
+        } else if db.is_failed(job)? {
+            "failed"
+        } else {
+            ""
+        };
         entries.push(JobStatus {
             worker,
+            take_time,
             start_time,
             start_cmd,
             finish_result,
             finish_time,
             finish_data,
+            user_time: usage.map_or(Paw::Absent, |u| {
+                Paw::Present(Secs(u.user_time.as_secs_f64()))
+            }),
+            sys_time: usage.map_or(Paw::Absent, |u| {
+                Paw::Present(Secs(u.system_time.as_secs_f64()))
+            }),
+            max_rss: usage.map_or(Paw::Absent, |u| Paw::Present(Bytes(u.max_rss as f64))),
+            blocks_in: usage.map_or(Paw::Absent, |u| Paw::Present(u.in_blocks)),
+            blocks_out: usage.map_or(Paw::Absent, |u| Paw::Present(u.out_blocks)),
+            state,
         })
     }
     print!("{}", Table::new(entries).with(Style::pseudo_clean()));
     Ok(())
 }
 
+#[derive(Tabled)]
+struct Stats {
+    id: u32,
+    queued: u64,
+    taken: u64,
+    running: u64,
+    succeeded: u64,
+    failed: u64,
+    run_mean: Paw<Secs>,
+    run_median: Paw<Secs>,
+    run_p95: Paw<Secs>,
+    wait_mean: Paw<Secs>,
+    wait_median: Paw<Secs>,
+    wait_p95: Paw<Secs>,
+    cpu_mean: Paw<Secs>,
+    cpu_median: Paw<Secs>,
+    cpu_p95: Paw<Secs>,
+    rss_mean: Paw<Bytes>,
+    rss_median: Paw<Bytes>,
+    rss_p95: Paw<Bytes>,
+}
+
+/// A duration in seconds, formatted for people.
+struct Secs(f64);
+
+impl Display for Secs {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let secs = self.0;
+        if secs < 60.0 {
+            return write!(f, "{:.1}s", secs);
+        }
+        let secs = secs.round() as u64;
+        let (h, m, s) = (secs / 3600, secs / 60 % 60, secs % 60);
+        if h > 0 {
+            write!(f, "{}h{:02}m{:02}s", h, m, s)
+        } else {
+            write!(f, "{}m{:02}s", m, s)
+        }
+    }
+}
+
+/// A number of bytes, formatted for people.
+struct Bytes(f64);
+
+impl Display for Bytes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut n = self.0;
+        for unit in &["B", "KiB", "MiB", "GiB"] {
+            if n < 1024.0 {
+                return write!(f, "{:.1}{}", n, unit);
+            }
+            n /= 1024.0;
+        }
+        write!(f, "{:.1}TiB", n)
+    }
+}
+
+fn print_stats(stats: Vec<jerbs::TaskStats>, verbose: bool) {
+    if verbose {
+        fn summary<T>(s: Option<jerbs::Summary>, f: fn(f64) -> T) -> (Paw<T>, Paw<T>, Paw<T>) {
+            match s {
+                Some(s) => (
+                    Paw::Present(f(s.mean)),
+                    Paw::Present(f(s.median)),
+                    Paw::Present(f(s.p95)),
+                ),
+                None => (Paw::Absent, Paw::Absent, Paw::Absent),
+            }
+        }
+        let entries: Vec<_> = stats
+            .into_iter()
+            .map(|s| {
+                let (run_mean, run_median, run_p95) = summary(s.run_time, Secs);
+                let (wait_mean, wait_median, wait_p95) = summary(s.queue_wait, Secs);
+                let (cpu_mean, cpu_median, cpu_p95) = summary(s.cpu_time, Secs);
+                let (rss_mean, rss_median, rss_p95) = summary(s.max_rss, Bytes);
+                Stats {
+                    id: s.task,
+                    queued: s.queued,
+                    taken: s.taken,
+                    running: s.running,
+                    succeeded: s.succeeded,
+                    failed: s.failed,
+                    run_mean,
+                    run_median,
+                    run_p95,
+                    wait_mean,
+                    wait_median,
+                    wait_p95,
+                    cpu_mean,
+                    cpu_median,
+                    cpu_p95,
+                    rss_mean,
+                    rss_median,
+                    rss_p95,
+                }
+            })
+            .collect();
+        print!("{}", Table::new(entries).with(Style::pseudo_clean()));
+    } else {
+        let summary = |s: Option<jerbs::Summary>| match s {
+            Some(s) => format!("{}\t{}\t{}", s.mean, s.median, s.p95),
+            None => "\t\t".to_owned(),
+        };
+        for s in stats {
+            println!(
+                "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                s.task,
+                s.queued,
+                s.taken,
+                s.running,
+                s.succeeded,
+                s.failed,
+                summary(s.run_time),
+                summary(s.queue_wait),
+                summary(s.cpu_time),
+                summary(s.max_rss)
+            );
+        }
+    }
+}
+
+#[derive(Tabled)]
+struct TaskEta {
+    id: u32,
+    remaining: u64,
+    eta: Paw<Time>,
+    time_left: Paw<Secs>,
+}
+
+fn print_eta(eta: jerbs::Eta, verbose: bool) {
+    let remaining = eta.tasks.iter().map(|t| t.remaining).sum::<u64>();
+    if verbose {
+        let now = Time(
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_secs_f64(),
+        );
+        let entries: Vec<_> = eta
+            .tasks
+            .into_iter()
+            .map(|t| TaskEta {
+                id: t.task,
+                remaining: t.remaining,
+                eta: t.drained.map_or(Paw::What, Paw::Present),
+                time_left: t
+                    .drained
+                    .map_or(Paw::What, |t| Paw::Present(Secs(t.since(now)))),
+            })
+            .collect();
+        print!("{}", Table::new(entries).with(Style::pseudo_clean()));
+        match eta.drained {
+            Some(drained) if remaining > 0 => println!(
+                "{} repetitions remaining, {} workers; done at {} (in {})",
+                remaining,
+                eta.workers,
+                drained,
+                Secs(drained.since(now))
+            ),
+            _ if remaining > 0 => println!(
+                "{} repetitions remaining, {} workers; can't estimate completion",
+                remaining, eta.workers
+            ),
+            _ => println!("no repetitions remaining"),
+        }
+    } else {
+        let time = |t: Option<Time>| t.map_or(String::new(), |t| t.0.to_string());
+        for t in eta.tasks {
+            println!("{}\t{}\t{}", t.task, t.remaining, time(t.drained));
+        }
+        println!("all\t{}\t{}", remaining, time(eta.drained));
+    }
+}
+
 #[derive(Tabled)]
 struct RunningStatus {
     worker: String,
-    start_time: Time,
+    start_time: Paw<Time>,
     start_cmd: Command,
 }
 
@@ -306,7 +981,12 @@ fn print_running_statuses(
     for job in jobs.into_iter() {
         let worker = db.get_job_worker(job)?;
         let start = db.get_job_start(job)?;
-        let start_time = start.as_ref().unwrap().time;
+        let start_time = start
+            .as_ref()
+            .unwrap()
+            .time
+            .map(Paw::Present)
+            .unwrap_or(Paw::What);
         let start_cmd = start.unwrap().cmd;
         entries.push(RunningStatus {
             worker,
@@ -356,6 +1036,15 @@ fn main() -> jerbs::Result<()> {
             let priority = args
                 .value_of("priority")
                 .map(|x| x.parse().expect("priority must be integer"));
+            let retry = args.value_of("max-attempts").map(|x| RetryPolicy {
+                max_attempts: x.parse().expect("max-attempts must be integer"),
+                delay: args
+                    .value_of("retry-delay")
+                    .map_or(Duration::ZERO, parse_duration),
+                backoff: args
+                    .value_of("backoff")
+                    .map_or(2.0, |x| x.parse().expect("backoff must be a number")),
+            });
             let mut db = Db::open(path)?;
             let id = if let Some(data) = args.value_of("data") {
                 db.new_job(data.as_bytes(), count, priority)?
@@ -363,7 +1052,32 @@ fn main() -> jerbs::Result<()> {
                 let data = read_data();
                 db.new_job(&data, count, priority)?
             };
+            if retry.is_some() {
+                db.set_retry_policy(id, retry)?;
+            }
+            let limits = parse_limits(args);
+            if limits != Limits::default() {
+                db.set_limits(id, &limits)?;
+            }
+            for (arg, condition) in &[
+                ("after-ok", Condition::Ok),
+                ("after-notok", Condition::NotOk),
+                ("after-any", Condition::Any),
+            ] {
+                for after in args.values_of(arg).into_iter().flatten() {
+                    let after = after.parse().expect("job ids are integers");
+                    db.add_dependency(id, after, *condition)?;
+                }
+            }
+            if args.is_present("preemptible") {
+                db.set_preemptible(id, true)?;
+            }
             println!("{}", id);
+            if args.is_present("preempt") {
+                for job in db.preempt(id, libc::SIGTERM, PREEMPT_WAIT)? {
+                    eprintln!("preempting job {}", job);
+                }
+            }
         }
         ("modify", Some(args)) => {
             let task = args
@@ -384,10 +1098,17 @@ fn main() -> jerbs::Result<()> {
             if let Some(prio) = prio {
                 db.set_priority(task, prio)?;
             }
+            let limits = parse_limits(args);
+            if args.is_present("clear-limits") {
+                db.set_limits(task, &limits)?;
+            } else if limits != Limits::default() {
+                db.set_limits(task, &limits.or(db.get_limits(task)?))?;
+            }
         }
         ("list-available", Some(args)) => {
             let verbose = args.is_present("verbose");
-            let db = Db::open(path)?;
+            let mut db = Db::open(path)?;
+            db.reclaim_expired()?;
             let ids = db.job_ids_vec()?;
             if verbose {
                 let mut entries = Vec::new();
@@ -404,6 +1125,25 @@ fn main() -> jerbs::Result<()> {
                     });
                 }
                 print!("{}", Table::new(entries).with(Style::pseudo_clean()));
+                let blocked = db.blocked_ids_vec()?;
+                if !blocked.is_empty() {
+                    let mut entries = Vec::new();
+                    for id in blocked {
+                        let blockers = db.get_blockers(id)?;
+                        let blockers: Vec<_> = blockers.iter().map(|b| b.to_string()).collect();
+                        let data = db.get_data(id)?;
+                        let data = std::str::from_utf8(&data).unwrap_or("<data>");
+                        entries.push(BlockedTask {
+                            id,
+                            count: db.get_count(id)?,
+                            priority: db.get_priority(id)?,
+                            blocked: blockers.join(", "),
+                            data: data.to_owned(),
+                        });
+                    }
+                    println!("blocked:");
+                    print!("{}", Table::new(entries).with(Style::pseudo_clean()));
+                }
             } else {
                 for id in ids {
                     println!("{}", id);
@@ -425,24 +1165,59 @@ fn main() -> jerbs::Result<()> {
                 .unwrap()
                 .parse()
                 .expect("job ids are integers");
-            let count = Db::open(path)?.get_count(id)?;
-            println!("{}", count);
+            let mut db = Db::open(path)?;
+            db.reclaim_expired()?;
+            println!("{}", db.get_count(id)?);
         }
         ("take", Some(args)) => {
             let mut db = Db::open(path)?;
             let worker = args.value_of("worker-id").unwrap();
             let wait = args.is_present("wait");
-            if wait {
-                todo!("take --wait")
+            let timeout = args.value_of("timeout").map(parse_duration);
+            let lease = args.value_of("lease").map(parse_duration);
+            let job = if wait {
+                db.take_with_id_blocking(worker, lease, timeout)?
             } else {
-                let job = db.take(worker)?;
-                if let Some(job) = job {
-                    io::stdout().write_all(&job.data).unwrap();
-                } else {
-                    std::process::exit(2);
+                db.take_with_id(worker, lease)?
+            };
+            if let Some((job, id)) = job {
+                if args.is_present("track-pid") {
+                    // This process is about to exit; the worker that ran it is the one doing the job.
+                    db.set_job_process(id, &Process::parent()?)?;
                 }
+                print_job(&db, args, id, &job.data)?;
+            } else {
+                std::process::exit(2);
             }
         }
+        ("kill", Some(args)) => {
+            let id = args
+                .value_of("job-id")
+                .unwrap()
+                .parse()
+                .expect("job ids are integers");
+            let signal = args.value_of("signal").map_or(libc::SIGTERM, parse_signal);
+            Db::open(path)?.cancel(id, signal)?;
+        }
+        (cmd @ "suspend", Some(args)) | (cmd @ "resume", Some(args)) => {
+            let id = args
+                .value_of("job-id")
+                .unwrap()
+                .parse()
+                .expect("job ids are integers");
+            Db::open(path)?.suspend(id, cmd == "suspend")?;
+        }
+        ("reap", Some(_)) => {
+            for job in Db::open(path)?.reap()? {
+                println!("{}", job);
+            }
+        }
+        ("heartbeat", Some(args)) => {
+            let mut db = Db::open(path)?;
+            let worker = args.value_of("worker-id").unwrap();
+            let id = worker_job(&mut db, args, worker)?;
+            db.heartbeat(id)?;
+        }
         ("list-running", Some(args)) => {
             let verbose = args.is_present("verbose");
             let mut db = Db::open(path)?;
@@ -454,6 +1229,16 @@ fn main() -> jerbs::Result<()> {
                 }
             }
         }
+        ("stats", Some(args)) => {
+            let mut db = Db::open(path)?;
+            db.reclaim_expired()?;
+            print_stats(db.task_stats()?, args.is_present("verbose"));
+        }
+        ("eta", Some(args)) => {
+            let mut db = Db::open(path)?;
+            db.reclaim_expired()?;
+            print_eta(db.eta()?, args.is_present("verbose"));
+        }
         ("list-taken", Some(args)) => {
             let verbose = args.is_present("verbose");
             let mut db = Db::open(path)?;
@@ -472,9 +1257,7 @@ fn main() -> jerbs::Result<()> {
                 .values_of_os("command")
                 .map(|args| args.map(|x| x.to_os_string().into_vec()).collect())
                 .unwrap_or(vec![]);
-            let id = db
-                .current_job(worker)?
-                .expect("worker currently has no job");
+            let id = worker_job(&mut db, args, worker)?;
             db.log_start(id, logcmd)?;
         }
         ("log-finish", Some(args)) => {
@@ -485,57 +1268,71 @@ fn main() -> jerbs::Result<()> {
                 .unwrap()
                 .parse()
                 .expect("result must be int");
-            let id = db
-                .current_job(worker)?
-                .expect("worker currently has no job");
-            db.log_finish(id, result)?;
+            let id = worker_job(&mut db, args, worker)?;
+            if args.is_present("read-data") {
+                db.log_finish_data(id, result, &read_data())?;
+            } else {
+                db.log_finish(id, result)?;
+            }
         }
         ("monitor", Some(args)) => {
-            use std::os::unix::process::ExitStatusExt;
-            use std::process::Command;
-
-            let requeue = args.is_present("requeue");
             let mut db = Db::open(path)?;
             let worker = args.value_of("worker-id").unwrap();
-            let logcmd = args
+            let cmd: Vec<_> = args
                 .values_of_os("command")
                 .unwrap()
-                .map(|x| x.to_os_string().into_vec())
+                .map(|x| x.to_os_string())
                 .collect();
-            let id = db
-                .current_job(worker)?
-                .expect("worker currently has no job");
-            db.log_start(id, logcmd)?;
-            let mut cmd = args.values_of_os("command").unwrap();
-            let exe = cmd.next().unwrap();
-            let result = Command::new(exe).args(cmd).status();
-            let log_code;
-            let my_exit;
-            match result {
-                Ok(result) => {
-                    // In the logs, we record signals as 256 + SIGNAL so it's always possible to
-                    // distinguish them from regular exit codes.
-                    log_code = result
-                        .code()
-                        .unwrap_or_else(|| 256 + result.signal().unwrap());
-                    // In our return value, we report signals as 128 + SIGNAL (like bash), since we don't
-                    // have enough return value space to keep signals distinct from exit codes.
-                    my_exit = result
-                        .code()
-                        .unwrap_or_else(|| 128 + result.signal().unwrap());
+            let mut monitor = Monitor::from_args(args);
+            monitor.terminal = true;
+            monitor.children.forward_signals()?;
+            let exit = if args.is_present("take") {
+                let lease = args.value_of("lease").map(parse_duration);
+                let cmd = monitor::to_bytes(&cmd);
+                let take = |db: &mut Db| {
+                    monitor
+                        .children
+                        .take(path, || db.take_and_start(worker, lease, &cmd))
+                };
+                let job = if args.is_present("wait") {
+                    let timeout = args.value_of("wait-timeout").map(parse_duration);
+                    db.wait_for(timeout, take)?
+                } else {
+                    take(&mut db)?
+                };
+                match job {
+                    Some((context, cmd)) => monitor.supervise(&mut db, path, &context, cmd)?,
+                    None => 2,
                 }
-                Err(e) => {
-                    eprintln!("Failed to start command: {}", e);
-                    const EXIT_FAILED_TO_START: i32 = 512;
-                    log_code = EXIT_FAILED_TO_START;
-                    my_exit = -1;
+            } else {
+                let id = worker_job(&mut db, args, worker)?;
+                monitor.run(&mut db, path, id, &cmd)?
+            };
+            std::process::exit(exit);
+        }
+        ("run", Some(args)) => {
+            let worker = match args.value_of("worker-id") {
+                Some(worker) => worker.to_owned(),
+                None => {
+                    let process = Process::current()?;
+                    format!("{}:{}", process.host, process.pid)
                 }
+            };
+            let pool = Pool {
+                slots: args.value_of("jobs").map_or(1, |x| x.parse().unwrap()),
+                worker,
+                wait: args.is_present("wait"),
+                lease: args.value_of("lease").map(parse_duration),
+                monitor: Monitor::from_args(args),
+                command: args
+                    .values_of_os("command")
+                    .unwrap()
+                    .map(|x| x.to_os_string())
+                    .collect(),
+            };
+            if !pool.run(path)? {
+                std::process::exit(1);
             }
-            db.log_finish(id, log_code)?;
-            if requeue && log_code != 0 {
-                // TODO
-            }
-            std::process::exit(my_exit);
         }
         _ => build_app(BuildingHelp::Short).print_help()?,
     }
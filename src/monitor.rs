@@ -0,0 +1,751 @@
+//! Running a job's command, and logging what happened to it.
+
+use clap::ArgMatches;
+use jerbs::{Db, JobContext, JobId, Limits, Process, Usage};
+use signal_hook::consts::{SIGHUP, SIGINT, SIGQUIT, SIGTERM};
+use signal_hook::iterator::Signals;
+use std::collections::{HashSet, VecDeque};
+use std::ffi::OsString;
+use std::io::{self, Read, Write};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The last bytes written to a stream.
+struct Tail {
+    buf: VecDeque<u8>,
+    limit: usize,
+}
+
+impl Tail {
+    fn new(limit: usize) -> Self {
+        Tail {
+            buf: VecDeque::new(),
+            limit,
+        }
+    }
+
+    fn push(&mut self, data: &[u8]) {
+        let data = &data[data.len().saturating_sub(self.limit)..];
+        let excess = (self.buf.len() + data.len()).saturating_sub(self.limit);
+        self.buf.drain(..excess);
+        self.buf.extend(data);
+    }
+
+    fn to_vec(&self) -> Vec<u8> {
+        self.buf.iter().copied().collect()
+    }
+}
+
+/// Copy a child's output stream through to one of ours, keeping the tail of it.
+fn tee(
+    mut from: impl Read + Send + 'static,
+    mut to: impl Write + Send + 'static,
+    tail: &Arc<Mutex<Tail>>,
+) -> thread::JoinHandle<()> {
+    let tail = Arc::clone(tail);
+    thread::spawn(move || {
+        let mut buf = [0; 8192];
+        loop {
+            let n = match from.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(_) => break,
+            };
+            // Our own output going away shouldn't stop the command or the capture.
+            let _ = to.write_all(&buf[..n]).and_then(|()| to.flush());
+            tail.lock().unwrap().push(&buf[..n]);
+        }
+    })
+}
+
+/// How soon to retry a failed heartbeat (if the lease isn't due to expire sooner).
+const HEARTBEAT_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Renew a job's lease from a background thread until the returned sender is dropped. A failed
+/// heartbeat (e.g. if the database is busy) is retried until the lease has run out.
+fn spawn_heartbeat(
+    path: &str,
+    job: JobId,
+    lease: Duration,
+) -> (mpsc::Sender<()>, thread::JoinHandle<()>) {
+    let (stop, stopped) = mpsc::channel();
+    let path = path.to_owned();
+    let heartbeat = thread::spawn(move || {
+        let db = match Db::open(&path) {
+            Ok(db) => db,
+            Err(e) => return eprintln!("Failed to open database for heartbeats: {}", e),
+        };
+        let mut expires = Instant::now() + lease;
+        let mut interval = lease / 3;
+        while let Err(mpsc::RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+            match db.heartbeat(job) {
+                Ok(()) => {
+                    expires = Instant::now() + lease;
+                    interval = lease / 3;
+                }
+                Err(e) if Instant::now() >= expires => {
+                    return eprintln!("Heartbeat failed, and the lease has run out: {}", e);
+                }
+                Err(e) => {
+                    eprintln!("Heartbeat failed, retrying: {}", e);
+                    interval = interval.min(HEARTBEAT_RETRY_INTERVAL);
+                }
+            }
+        }
+    });
+    (stop, heartbeat)
+}
+
+/// How often to check whether a running job has been cancelled.
+const CONTROL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Carry out requests to control a running job's command (see [`Db::cancel`] and
+/// [`Db::suspend`]), until the returned sender is dropped. Suspensions are reported to the
+/// command's watchdog, if it has one, so it can stop the clock. The thread returns whether the job
+/// was cancelled.
+fn spawn_control(
+    path: &str,
+    job: JobId,
+    pid: u32,
+    watchdog: Option<mpsc::Sender<bool>>,
+) -> (mpsc::Sender<()>, thread::JoinHandle<bool>) {
+    let (stop, stopped) = mpsc::channel();
+    let path = path.to_owned();
+    let control = thread::spawn(move || {
+        let db = match Db::open(&path) {
+            Ok(db) => db,
+            Err(e) => {
+                eprintln!(
+                    "Failed to open database to check for control requests: {}",
+                    e
+                );
+                return false;
+            }
+        };
+        let mut suspended = false;
+        while let Err(mpsc::RecvTimeoutError::Timeout) = stopped.recv_timeout(CONTROL_INTERVAL) {
+            let requests = db
+                .get_cancel(job)
+                .and_then(|cancel| Ok((cancel, db.is_suspended(job)?)));
+            match requests {
+                // If the command has already exited, it wasn't cancelled; whatever it exited with
+                // is logged. Nor is there anything left to stop or continue (and its process group
+                // may be about to go away).
+                Ok((Some(_), _)) if has_exited(pid) => break,
+                Ok((None, suspend)) if suspend != suspended && has_exited(pid) => break,
+                Ok((Some(signal), _)) => {
+                    kill_group(pid, signal);
+                    // A stopped command won't handle the signal until it's continued.
+                    if suspended {
+                        kill_group(pid, libc::SIGCONT);
+                    }
+                    return true;
+                }
+                Ok((None, suspend)) if suspend != suspended => {
+                    kill_group(
+                        pid,
+                        if suspend {
+                            libc::SIGSTOP
+                        } else {
+                            libc::SIGCONT
+                        },
+                    );
+                    suspended = suspend;
+                    if let Some(watchdog) = &watchdog {
+                        // It's gone if the command already timed out.
+                        let _ = watchdog.send(suspended);
+                    }
+                    if let Err(e) = db.log_suspended(job, suspended) {
+                        eprintln!("Failed to log suspension: {}", e);
+                    }
+                }
+                Ok(_) => (),
+                Err(e) => eprintln!("Failed to check for control requests: {}", e),
+            }
+        }
+        false
+    });
+    (stop, control)
+}
+
+/// A job that's been taken and started, with the command to run for it.
+pub type Started = (JobContext, Vec<Vec<u8>>);
+
+/// The commands being run, so they can be signalled together.
+#[derive(Clone, Default)]
+pub struct Children(Arc<Mutex<Running>>);
+
+#[derive(Default)]
+struct Running {
+    /// Process ids of the running commands.
+    pids: HashSet<u32>,
+    /// Jobs we've taken that aren't logged as finished yet, with the paths of their databases.
+    jobs: Vec<(String, JobId)>,
+}
+
+impl Children {
+    /// Send a signal to each running command's process group. If no command is running, there's
+    /// nothing to pass it on to, so exit as the signal would have made us (like bash, with 128 +
+    /// SIGNAL); any job we've taken whose command isn't running is abandoned, rather than left
+    /// started but never finished.
+    pub fn kill(&self, signal: i32) {
+        let running = self.0.lock().unwrap();
+        if running.pids.is_empty() {
+            for (path, job) in &running.jobs {
+                if let Err(e) = Db::open(path).and_then(|mut db| db.abandon(*job)) {
+                    eprintln!("Failed to abandon job {}: {}", job, e);
+                }
+            }
+            std::process::exit(128 + signal);
+        }
+        for &pid in running.pids.iter() {
+            kill_group(pid, signal);
+        }
+    }
+
+    /// Take and start a job with `take`, and keep track of it until it's finished. A signal that
+    /// arrives in the meantime waits for the take to finish, so it can't miss the job.
+    pub fn take(
+        &self,
+        path: &str,
+        take: impl FnOnce() -> jerbs::Result<Option<Started>>,
+    ) -> jerbs::Result<Option<Started>> {
+        let mut running = self.0.lock().unwrap();
+        let job = take()?;
+        if let Some((context, _)) = &job {
+            running.jobs.push((path.to_owned(), context.job));
+        }
+        Ok(job)
+    }
+
+    fn release(&self, job: JobId) {
+        self.0.lock().unwrap().jobs.retain(|&(_, j)| j != job);
+    }
+
+    fn insert(&self, pid: u32) {
+        self.0.lock().unwrap().pids.insert(pid);
+    }
+
+    fn remove(&self, pid: u32) {
+        self.0.lock().unwrap().pids.remove(&pid);
+    }
+
+    /// Pass the signals that usually end a process on to the running commands, instead; so
+    /// they're ended by them, and we can log how.
+    pub fn forward_signals(&self) -> jerbs::Result<()> {
+        let mut signals = Signals::new([SIGHUP, SIGINT, SIGQUIT, SIGTERM])?;
+        let children = self.clone();
+        thread::spawn(move || {
+            for signal in signals.forever() {
+                children.kill(signal);
+            }
+        });
+        Ok(())
+    }
+}
+
+/// Whether a child has exited (or been reaped). The child isn't reaped, so its pid can't be reused
+/// while we're looking at it.
+#[cfg(target_os = "linux")]
+fn has_exited(pid: u32) -> bool {
+    let mut info = unsafe { std::mem::zeroed::<libc::siginfo_t>() };
+    let status = unsafe {
+        libc::waitid(
+            libc::P_PID,
+            pid as libc::id_t,
+            &mut info,
+            libc::WEXITED | libc::WNOHANG | libc::WNOWAIT,
+        )
+    };
+    // With WNOHANG, si_pid is left zero if the child is still running.
+    status != 0 || unsafe { info.si_pid() } != 0
+}
+
+/// Elsewhere, we can't tell without reaping the child, so assume it's still running.
+#[cfg(not(target_os = "linux"))]
+fn has_exited(_pid: u32) -> bool {
+    false
+}
+
+/// Send a signal to the process group led by the given process.
+fn kill_group(pid: u32, signal: i32) {
+    unsafe {
+        libc::kill(-(pid as libc::pid_t), signal);
+    }
+}
+
+/// Terminate a command's process group if it's still running after the timeout, and kill it if
+/// it's still running `kill_after` that. Time the command spends suspended doesn't count: send
+/// `true` when it's suspended and `false` when it's resumed. Stops watching when the returned
+/// sender is dropped, which must happen before the command is reaped; the thread returns whether
+/// it sent SIGTERM, i.e. whether the command timed out.
+fn spawn_watchdog(
+    pid: u32,
+    timeout: Duration,
+    kill_after: Duration,
+) -> (mpsc::Sender<bool>, thread::JoinHandle<bool>) {
+    let (control, controls) = mpsc::channel();
+    let watchdog = thread::spawn(move || {
+        let mut deadline = Instant::now() + timeout;
+        let mut suspended_since = None;
+        loop {
+            let control = match suspended_since {
+                Some(_) => controls
+                    .recv()
+                    .map_err(|_| mpsc::RecvTimeoutError::Disconnected),
+                None => controls.recv_timeout(deadline.saturating_duration_since(Instant::now())),
+            };
+            match control {
+                Ok(true) => suspended_since = suspended_since.or_else(|| Some(Instant::now())),
+                Ok(false) => {
+                    if let Some(since) = suspended_since.take() {
+                        deadline += since.elapsed();
+                    }
+                }
+                Err(mpsc::RecvTimeoutError::Timeout) => break,
+                Err(mpsc::RecvTimeoutError::Disconnected) => return false,
+            }
+        }
+        // If the command exited on its own just now, it didn't time out.
+        if has_exited(pid) {
+            return false;
+        }
+        kill_group(pid, libc::SIGTERM);
+        let deadline = Instant::now() + kill_after;
+        loop {
+            match controls.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok(_) => continue,
+                Err(mpsc::RecvTimeoutError::Timeout) => kill_group(pid, libc::SIGKILL),
+                Err(mpsc::RecvTimeoutError::Disconnected) => (),
+            }
+            break;
+        }
+        true
+    });
+    (control, watchdog)
+}
+
+/// Our standard input, if it's a terminal and we're in its foreground process group.
+fn foreground_tty() -> Option<libc::c_int> {
+    let tty = libc::STDIN_FILENO;
+    let foreground = unsafe { libc::isatty(tty) == 1 && libc::tcgetpgrp(tty) == libc::getpgrp() };
+    if foreground {
+        Some(tty)
+    } else {
+        None
+    }
+}
+
+/// Make a process group the terminal's foreground group. Async-signal-safe.
+fn set_foreground(tty: libc::c_int, pgrp: libc::pid_t) -> io::Result<()> {
+    unsafe {
+        // Unless we're in the foreground group, the terminal would stop us for this with SIGTTOU.
+        let mut ttou = std::mem::zeroed();
+        let mut mask = std::mem::zeroed();
+        libc::sigemptyset(&mut ttou);
+        libc::sigaddset(&mut ttou, libc::SIGTTOU);
+        libc::pthread_sigmask(libc::SIG_BLOCK, &ttou, &mut mask);
+        let result = match libc::tcsetpgrp(tty, pgrp) {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        };
+        libc::pthread_sigmask(libc::SIG_SETMASK, &mask, std::ptr::null_mut());
+        result
+    }
+}
+
+/// If a child that has the terminal was stopped from it (as by ^Z), stop too, as we would have if
+/// we were in its process group; when we're continued, so is it.
+fn follow_stop(child: &Child, tty: Option<libc::c_int>, signal: libc::c_int) -> io::Result<()> {
+    // A SIGSTOP is from `suspend`; it isn't ours to follow.
+    if let (Some(tty), libc::SIGTSTP | libc::SIGTTIN | libc::SIGTTOU) = (tty, signal) {
+        set_foreground(tty, unsafe { libc::getpgrp() })?;
+        unsafe { libc::kill(libc::getpid(), signal) };
+        set_foreground(tty, child.id() as libc::pid_t)?;
+        kill_group(child.id(), libc::SIGCONT);
+    }
+    Ok(())
+}
+
+/// Wait for a child to exit without reaping it, following its stops (see [`follow_stop`]).
+#[cfg(target_os = "linux")]
+fn wait_for_exit(child: &Child, tty: Option<libc::c_int>) -> io::Result<()> {
+    let stops = if tty.is_some() { libc::WSTOPPED } else { 0 };
+    loop {
+        let mut info = unsafe { std::mem::zeroed::<libc::siginfo_t>() };
+        let status = unsafe {
+            libc::waitid(
+                libc::P_PID,
+                child.id() as libc::id_t,
+                &mut info,
+                libc::WEXITED | libc::WNOWAIT | stops,
+            )
+        };
+        if status < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() != io::ErrorKind::Interrupted {
+                return Err(e);
+            }
+            continue;
+        }
+        if info.si_code != libc::CLD_STOPPED {
+            return Ok(());
+        }
+        // Consume the stop; an exit since then stays waiting.
+        let mut consumed = unsafe { std::mem::zeroed::<libc::siginfo_t>() };
+        unsafe {
+            libc::waitid(
+                libc::P_PID,
+                child.id() as libc::id_t,
+                &mut consumed,
+                libc::WSTOPPED | libc::WNOHANG,
+            );
+        }
+        follow_stop(child, tty, unsafe { info.si_status() })?;
+    }
+}
+
+/// Wait for a child to exit, collecting its resource usage (which [`Child::wait`] can't).
+/// `exited` is called once it has exited, before it's reaped and its process group could be
+/// reused. (Elsewhere than Linux, we can't wait without reaping, so it's called after.)
+fn wait_with_usage(
+    child: &Child,
+    tty: Option<libc::c_int>,
+    exited: impl FnOnce(),
+) -> io::Result<(ExitStatus, Usage)> {
+    #[cfg(target_os = "linux")]
+    {
+        wait_for_exit(child, tty)?;
+        exited();
+    }
+    let options = if tty.is_some() { libc::WUNTRACED } else { 0 };
+    let mut status = 0;
+    let mut usage = unsafe { std::mem::zeroed::<libc::rusage>() };
+    loop {
+        let pid = child.id() as libc::pid_t;
+        if unsafe { libc::wait4(pid, &mut status, options, &mut usage) } < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() != io::ErrorKind::Interrupted {
+                return Err(e);
+            }
+            continue;
+        }
+        if !libc::WIFSTOPPED(status) {
+            break;
+        }
+        follow_stop(child, tty, libc::WSTOPSIG(status))?;
+    }
+    #[cfg(not(target_os = "linux"))]
+    exited();
+    let time = |t: libc::timeval| {
+        Duration::from_secs(t.tv_sec as u64) + Duration::from_micros(t.tv_usec as u64)
+    };
+    let usage = Usage {
+        user_time: time(usage.ru_utime),
+        system_time: time(usage.ru_stime),
+        // Linux and the BSDs report this in kilobytes; macOS, in bytes.
+        max_rss: usage.ru_maxrss as u64 * if cfg!(target_os = "macos") { 1 } else { 1024 },
+        in_blocks: usage.ru_inblock as u64,
+        out_blocks: usage.ru_oublock as u64,
+    };
+    Ok((ExitStatus::from_raw(status), usage))
+}
+
+/// Apply a command's resource limits to the current process.
+fn apply_limits(limits: &Limits) -> io::Result<()> {
+    let rlimit = |limit: u64| libc::rlimit {
+        rlim_cur: limit as libc::rlim_t,
+        rlim_max: limit as libc::rlim_t,
+    };
+    let check = |status| match status {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    };
+    if let Some(max_memory) = limits.max_memory {
+        check(unsafe { libc::setrlimit(libc::RLIMIT_AS, &rlimit(max_memory)) })?;
+    }
+    if let Some(max_cpu_time) = limits.max_cpu_time {
+        // The limit is in whole seconds; round up, so a short limit isn't no time at all.
+        let secs = max_cpu_time.as_secs_f64().ceil() as u64;
+        check(unsafe { libc::setrlimit(libc::RLIMIT_CPU, &rlimit(secs)) })?;
+    }
+    if let Some(nice) = limits.nice {
+        check(unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) })?;
+    }
+    Ok(())
+}
+
+pub fn to_bytes(cmd: &[OsString]) -> Vec<Vec<u8>> {
+    cmd.iter().map(|x| x.as_bytes().to_vec()).collect()
+}
+
+/// How to run a job's command.
+pub struct Monitor {
+    /// Put the job back in the queue if its command fails.
+    pub requeue: bool,
+    /// Record the monitoring process, so the job can be reaped if it dies.
+    pub track_pid: bool,
+    pub capture_stdout: bool,
+    pub capture_stderr: bool,
+    /// Maximum number of bytes of output to log.
+    pub capture_limit: usize,
+    /// Write the job's data to the command's standard input.
+    pub stdin_data: bool,
+    /// Limits on the command, overriding those of the job's task.
+    pub limits: Limits,
+    /// How long after terminating a command that timed out to kill it.
+    pub kill_after: Duration,
+    /// Commands are registered here while they run. Each is started in its own process group,
+    /// so it doesn't receive signals meant for us unless we pass them on.
+    pub children: Children,
+    /// If we're in the foreground of a terminal, make the command's process group the
+    /// foreground group while it runs, so it can read from the terminal (and ^C and ^Z go to it).
+    /// Only one command can have the terminal, so pools leave this off.
+    pub terminal: bool,
+}
+
+impl Monitor {
+    /// Configure from the arguments shared by `monitor` and `run`.
+    pub fn from_args(args: &ArgMatches) -> Self {
+        let capture = args.value_of("capture");
+        Monitor {
+            requeue: args.is_present("requeue"),
+            track_pid: args.is_present("track-pid"),
+            capture_stdout: matches!(capture, Some("stdout") | Some("both")),
+            capture_stderr: matches!(capture, Some("stderr") | Some("both")),
+            capture_limit: args.value_of("capture-limit").map_or(65536, |x| {
+                x.parse().expect("capture-limit must be a number of bytes")
+            }),
+            stdin_data: args.is_present("stdin-data"),
+            limits: crate::parse_limits(args),
+            kill_after: args
+                .value_of("kill-after")
+                .map_or(Duration::from_secs(10), crate::parse_duration),
+            children: Children::default(),
+            terminal: false,
+        }
+    }
+
+    /// Run a job's command, logging its start and finish. Placeholders in the command's arguments
+    /// are replaced with the job's values (see [`jerbs::JobContext::expand`]), which are also
+    /// exported to its environment. Returns the exit code to report for it.
+    pub fn run(&self, db: &mut Db, path: &str, id: JobId, cmd: &[OsString]) -> jerbs::Result<i32> {
+        let context = db.get_job_context(id)?;
+        let cmd: Vec<_> = to_bytes(cmd).iter().map(|x| context.expand(x)).collect();
+        db.log_start(id, cmd.clone())?;
+        self.supervise(db, path, &context, cmd)
+    }
+
+    /// Run the command for a job that has been logged as started (see [`Db::take_and_start`]), and
+    /// log how it finished. If that fails, the job is abandoned rather than left unfinished.
+    pub fn supervise(
+        &self,
+        db: &mut Db,
+        path: &str,
+        context: &JobContext,
+        cmd: Vec<Vec<u8>>,
+    ) -> jerbs::Result<i32> {
+        let result = self.supervise_inner(db, path, context, cmd);
+        if result.is_err() {
+            if let Err(e) = db.abandon(context.job) {
+                eprintln!("Failed to abandon job: {}", e);
+            }
+        }
+        self.children.release(context.job);
+        result
+    }
+
+    fn supervise_inner(
+        &self,
+        db: &mut Db,
+        path: &str,
+        context: &JobContext,
+        cmd: Vec<Vec<u8>>,
+    ) -> jerbs::Result<i32> {
+        let id = context.job;
+        let limits = self.limits.or(db.get_limits(context.task)?);
+        if self.track_pid {
+            db.set_job_process(id, &Process::current()?)?;
+        }
+        let cmd: Vec<_> = cmd.into_iter().map(OsString::from_vec).collect();
+        let heartbeat = db
+            .get_lease(id)?
+            .map(|lease| spawn_heartbeat(path, id, lease));
+        let mut command = Command::new(&cmd[0]);
+        command.args(&cmd[1..]);
+        // The command may be run from another directory, so give it a path that will still work.
+        let db_path = std::fs::canonicalize(path).unwrap_or_else(|_| path.into());
+        command
+            .env("JERBS_DB", db_path)
+            .env("JERBS_JOB_ID", context.job.to_string())
+            .env("JERBS_TASK_ID", context.task.to_string())
+            .env("JERBS_WORKER", &context.worker)
+            .env("JERBS_REPETITION", context.repetition.to_string());
+        if self.capture_stdout {
+            command.stdout(Stdio::piped());
+        }
+        if self.capture_stderr {
+            command.stderr(Stdio::piped());
+        }
+        if self.stdin_data {
+            command.stdin(Stdio::piped());
+        }
+        command.process_group(0);
+        let tty = if self.terminal {
+            foreground_tty()
+        } else {
+            None
+        };
+        #[cfg(target_os = "linux")]
+        let parent = std::process::id();
+        // Only async-signal-safe calls here: see [`CommandExt::pre_exec`].
+        unsafe {
+            command.pre_exec(move || {
+                // If we're killed, the command shouldn't keep running unsupervised. (Only Linux
+                // has a way to arrange that.)
+                #[cfg(target_os = "linux")]
+                {
+                    if libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL) != 0 {
+                        return Err(io::Error::last_os_error());
+                    }
+                    if libc::getppid() as u32 != parent {
+                        // We missed the death signal: monitor is already gone. Nothing here may
+                        // allocate, so the error is just an errno.
+                        return Err(io::Error::from_raw_os_error(libc::ESRCH));
+                    }
+                }
+                if let Some(tty) = tty {
+                    set_foreground(tty, libc::getpid())?;
+                }
+                apply_limits(&limits)
+            });
+        }
+        let output = Arc::new(Mutex::new(Tail::new(self.capture_limit)));
+        let result = command.spawn().and_then(|mut child| {
+            self.children.insert(child.id());
+            // Written from another thread, so a command that doesn't read all its input before
+            // producing output can't deadlock with us.
+            let feeder = child.stdin.take().map(|mut stdin| {
+                let data = context.data.clone();
+                thread::spawn(move || {
+                    // The command may exit without reading it all; that's its business.
+                    let _ = stdin.write_all(&data);
+                })
+            });
+            let tees: Vec<_> = child
+                .stdout
+                .take()
+                .map(|out| tee(out, io::stdout(), &output))
+                .into_iter()
+                .chain(
+                    child
+                        .stderr
+                        .take()
+                        .map(|err| tee(err, io::stderr(), &output)),
+                )
+                .collect();
+            let watchdog = limits
+                .timeout
+                .map(|timeout| spawn_watchdog(child.id(), timeout, self.kill_after));
+            let (stop_control, control) = spawn_control(
+                path,
+                id,
+                child.id(),
+                watchdog.as_ref().map(|(watchdog, _)| watchdog.clone()),
+            );
+            let mut cancelled = false;
+            let mut timed_out = false;
+            let status = wait_with_usage(&child, tty, || {
+                // The control thread has to let go of the watchdog before the watchdog can stop.
+                drop(stop_control);
+                cancelled = control.join().unwrap();
+                timed_out = watchdog.is_some_and(|(stop, watchdog)| {
+                    drop(stop);
+                    watchdog.join().unwrap()
+                });
+            });
+            self.children.remove(child.id());
+            for tee in tees {
+                tee.join().unwrap();
+            }
+            if let Some(feeder) = feeder {
+                feeder.join().unwrap();
+            }
+            status.map(|(status, usage)| (status, usage, timed_out, cancelled))
+        });
+        if let Some(tty) = tty {
+            // The command may have got the terminal even if it failed to start.
+            if let Err(e) = set_foreground(tty, unsafe { libc::getpgrp() }) {
+                eprintln!("Failed to take back the terminal: {}", e);
+            }
+        }
+        if let Some((stop, heartbeat)) = heartbeat {
+            drop(stop);
+            heartbeat.join().unwrap();
+        }
+        let usage = result.as_ref().ok().map(|&(_, usage, _, _)| usage);
+        let log_code;
+        let my_exit;
+        match result {
+            Ok((result, _, _, true)) => {
+                log_code = if db.is_preempted(id)? {
+                    eprintln!("Job preempted");
+                    jerbs::RESULT_PREEMPTED
+                } else {
+                    eprintln!("Job cancelled");
+                    jerbs::RESULT_CANCELLED
+                };
+                my_exit = result
+                    .code()
+                    .unwrap_or_else(|| 128 + result.signal().unwrap());
+            }
+            Ok((_, _, true, false)) => {
+                eprintln!("Command timed out");
+                log_code = jerbs::RESULT_TIMED_OUT;
+                // Like timeout(1).
+                my_exit = 124;
+            }
+            Ok((result, _, false, false)) => {
+                // In the logs, we record signals as 256 + SIGNAL so it's always possible to
+                // distinguish them from regular exit codes.
+                log_code = result
+                    .code()
+                    .unwrap_or_else(|| 256 + result.signal().unwrap());
+                // In our return value, we report signals as 128 + SIGNAL (like bash), since we don't
+                // have enough return value space to keep signals distinct from exit codes.
+                my_exit = result
+                    .code()
+                    .unwrap_or_else(|| 128 + result.signal().unwrap());
+            }
+            Err(e) => {
+                eprintln!("Failed to start command: {}", e);
+                log_code = jerbs::RESULT_FAILED_TO_START;
+                my_exit = -1;
+            }
+        }
+        if self.capture_stdout || self.capture_stderr {
+            let output = output.lock().unwrap().to_vec();
+            db.log_finish_data(id, log_code, &output)?;
+        } else {
+            db.log_finish(id, log_code)?;
+        }
+        if let Some(usage) = usage {
+            db.set_job_usage(id, &usage)?;
+        }
+        // A task's retry policy, if it has one, takes precedence over --requeue-on-fail.
+        if self.requeue
+            && log_code != 0
+            && log_code != jerbs::RESULT_CANCELLED
+            && !db.is_requeued(id)?
+            && !db.is_failed(id)?
+        {
+            db.requeue(id)?;
+        }
+        Ok(my_exit)
+    }
+}
@@ -0,0 +1,133 @@
+//! A pool of worker slots, each taking jobs and running a command for them.
+
+use crate::monitor::{to_bytes, Monitor, Started};
+use jerbs::{has_placeholders, Db};
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+use std::ffi::OsString;
+use std::os::unix::ffi::OsStrExt;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// How often an idle slot checks whether it should stop.
+const STOP_CHECK_INTERVAL: Duration = Duration::from_millis(250);
+
+pub struct Pool {
+    /// Number of jobs to run at once.
+    pub slots: usize,
+    /// Prefix of each slot's worker id.
+    pub worker: String,
+    /// Wait for more jobs when the queue is empty, instead of stopping.
+    pub wait: bool,
+    pub lease: Option<Duration>,
+    pub monitor: Monitor,
+    /// The command to run. Unless it contains placeholders or the data is written to its standard
+    /// input, each job's data is appended as its last argument.
+    pub command: Vec<OsString>,
+}
+
+/// State shared by a pool's slots.
+#[derive(Default)]
+struct Shared {
+    /// Set once we've been interrupted; slots finish their jobs, but don't take more.
+    stopping: AtomicBool,
+    /// Number of slots running (or taking) a job.
+    busy: AtomicUsize,
+    /// Held while a slot tries to take a job, so others can't see the queue as drained between
+    /// its take and its job counting as busy.
+    taking: Mutex<()>,
+}
+
+impl Pool {
+    /// Run jobs until the queue drains (or, with `wait`, until interrupted). The first SIGINT or
+    /// SIGTERM stops taking jobs; any after that are passed on to the running commands. Returns
+    /// whether all the jobs run succeeded, without being interrupted.
+    pub fn run(mut self, path: &str) -> jerbs::Result<bool> {
+        let children = self.monitor.children.clone();
+        let shared = Arc::new(Shared::default());
+        let mut signals = Signals::new([SIGINT, SIGTERM])?;
+        {
+            let shared = Arc::clone(&shared);
+            thread::spawn(move || {
+                for signal in signals.forever() {
+                    if !shared.stopping.swap(true, Ordering::SeqCst) {
+                        eprintln!("Letting running jobs finish; interrupt again to kill them.");
+                    } else {
+                        children.kill(signal);
+                    }
+                }
+            });
+        }
+        if !self.monitor.stdin_data && !self.command.iter().any(|x| has_placeholders(x.as_bytes()))
+        {
+            self.command.push("{data}".into());
+        }
+        let pool = Arc::new(self);
+        let slots: Vec<_> = (0..pool.slots)
+            .map(|slot| {
+                let pool = Arc::clone(&pool);
+                let shared = Arc::clone(&shared);
+                let path = path.to_owned();
+                thread::spawn(move || pool.run_slot(&path, slot, &shared))
+            })
+            .collect();
+        let mut ok = true;
+        for slot in slots {
+            ok &= slot.join().unwrap()?;
+        }
+        Ok(ok && !shared.stopping.load(Ordering::SeqCst))
+    }
+
+    fn run_slot(&self, path: &str, slot: usize, shared: &Shared) -> jerbs::Result<bool> {
+        let worker = format!("{}.{}", self.worker, slot);
+        let mut db = Db::open(path)?;
+        let cmd = to_bytes(&self.command);
+        let mut ok = true;
+        while !shared.stopping.load(Ordering::SeqCst) {
+            let (more, job) = self.try_take(&mut db, path, &worker, &cmd, shared)?;
+            let (context, cmd) = match job {
+                Some(job) => job,
+                None if !more => break,
+                None => {
+                    let take = |db: &mut Db| Ok(self.try_take(db, path, &worker, &cmd, shared)?.1);
+                    match db.wait_for(Some(STOP_CHECK_INTERVAL), take)? {
+                        Some(job) => job,
+                        None => continue,
+                    }
+                }
+            };
+            let result = self.monitor.supervise(&mut db, path, &context, cmd);
+            shared.busy.fetch_sub(1, Ordering::SeqCst);
+            ok &= result? == 0;
+        }
+        Ok(ok)
+    }
+
+    /// Try to take and start a job, counting this slot as busy if one was taken. Also returns
+    /// whether more jobs might turn up if none was.
+    fn try_take(
+        &self,
+        db: &mut Db,
+        path: &str,
+        worker: &str,
+        cmd: &[Vec<u8>],
+        shared: &Shared,
+    ) -> jerbs::Result<(bool, Option<Started>)> {
+        let _taking = shared.taking.lock().unwrap();
+        // Even without --wait, the queue isn't drained while our other slots are running jobs
+        // (which may be retried, or unblock others) or retries are backing off.
+        let more =
+            self.wait || shared.busy.load(Ordering::SeqCst) > 0 || db.has_pending_retries()?;
+        shared.busy.fetch_add(1, Ordering::SeqCst);
+        let job = self
+            .monitor
+            .children
+            .take(path, || db.take_and_start(worker, self.lease, cmd));
+        if !matches!(job, Ok(Some(_))) {
+            shared.busy.fetch_sub(1, Ordering::SeqCst);
+        }
+        Ok((more, job?))
+    }
+}
@@ -34,6 +34,26 @@ fn test_take() -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn test_take_wait() -> Result<()> {
+    let db_file = NamedTempFile::new()?;
+    let db = db_file.path();
+    cmd(db, &["init"])?.assert().success();
+    // nothing to take: should time out
+    cmd(db, &["take", "--wait", "--timeout", "0.2", "WORKERDATA"])?
+        .assert()
+        .code(2);
+    // work already available shouldn't require waiting
+    cmd(db, &["create", "-c", "1", "-d", "JOBDATA"])?
+        .assert()
+        .success();
+    cmd(db, &["take", "--wait", "WORKERDATA"])?
+        .assert()
+        .success()
+        .stdout("JOBDATA");
+    Ok(())
+}
+
 #[test]
 fn test_monitor() -> Result<()> {
     let db_file = NamedTempFile::new()?;
@@ -90,3 +110,901 @@ fn test_monitor() -> Result<()> {
         .failure();
     Ok(())
 }
+
+#[test]
+fn test_monitor_requeue() -> Result<()> {
+    let db_file = NamedTempFile::new()?;
+    let db = db_file.path();
+    cmd(db, &["init"])?.assert().success();
+    cmd(db, &["create", "-c", "1", "-d", "JOBDATA"])?
+        .assert()
+        .success();
+    // a failed job is put back in the queue
+    cmd(db, &["take", "WORKERDATA1"])?.assert().success();
+    cmd(db, &["monitor", "-r", "WORKERDATA1", "--", "false"])?
+        .assert()
+        .failure();
+    cmd(db, &["get-count", "1"])?
+        .assert()
+        .success()
+        .stdout("1\n");
+    // a successful one isn't
+    cmd(db, &["take", "WORKERDATA1"])?.assert().success();
+    cmd(db, &["monitor", "-r", "WORKERDATA1", "--", "true"])?
+        .assert()
+        .success();
+    cmd(db, &["get-count", "1"])?
+        .assert()
+        .success()
+        .stdout("0\n");
+    // both attempts are in the log
+    cmd(db, &["list-taken"])?
+        .assert()
+        .success()
+        .stdout("1\n2\n");
+    cmd(db, &["list-taken", "-v"])?.assert().success();
+    Ok(())
+}
+
+#[test]
+fn test_max_attempts() -> Result<()> {
+    let db_file = NamedTempFile::new()?;
+    let db = db_file.path();
+    cmd(db, &["init"])?.assert().success();
+    cmd(
+        db,
+        &["create", "-c", "1", "--max-attempts", "2", "-d", "JOBDATA"],
+    )?
+    .assert()
+    .success();
+    // the first failure is retried
+    cmd(db, &["take", "WORKERDATA1"])?.assert().success();
+    cmd(db, &["monitor", "WORKERDATA1", "--", "false"])?
+        .assert()
+        .failure();
+    // the second is final, even with --requeue-on-fail
+    cmd(db, &["take", "WORKERDATA1"])?.assert().success();
+    cmd(db, &["monitor", "-r", "WORKERDATA1", "--", "false"])?
+        .assert()
+        .failure();
+    cmd(db, &["take", "WORKERDATA1"])?.assert().code(2);
+    Ok(())
+}
+
+#[test]
+fn test_lease() -> Result<()> {
+    let db_file = NamedTempFile::new()?;
+    let db = db_file.path();
+    cmd(db, &["init"])?.assert().success();
+    cmd(db, &["create", "-c", "1", "-d", "JOBDATA"])?
+        .assert()
+        .success();
+    // monitor keeps the lease alive while the command runs
+    cmd(db, &["take", "--lease", "0.3", "WORKERDATA1"])?
+        .assert()
+        .success();
+    cmd(db, &["monitor", "WORKERDATA1", "--", "sleep", "1"])?
+        .assert()
+        .success();
+    cmd(db, &["take", "WORKERDATA2"])?.assert().code(2);
+    // a lease that isn't renewed expires, and the job can be taken again
+    cmd(db, &["modify", "1", "--add", "1"])?.assert().success();
+    cmd(db, &["take", "--lease", "0.1", "WORKERDATA1"])?
+        .assert()
+        .success();
+    cmd(db, &["heartbeat", "WORKERDATA1"])?.assert().success();
+    std::thread::sleep(std::time::Duration::from_millis(300));
+    cmd(db, &["take", "WORKERDATA2"])?
+        .assert()
+        .success()
+        .stdout("JOBDATA");
+    cmd(db, &["heartbeat", "WORKERDATA1"])?.assert().failure();
+    Ok(())
+}
+
+#[test]
+fn test_reap() -> Result<()> {
+    let db_file = NamedTempFile::new()?;
+    let db = db_file.path();
+    cmd(db, &["init"])?.assert().success();
+    cmd(db, &["create", "-c", "1", "-d", "JOBDATA"])?
+        .assert()
+        .success();
+    // take a job from a worker that exits right away
+    let jerbs = assert_cmd::cargo::cargo_bin("jerbs");
+    Command::new("sh")
+        .arg("-c")
+        .arg("\"$0\" \"$1\" take --track-pid WORKERDATA1")
+        .arg(jerbs)
+        .arg(db)
+        .assert()
+        .success();
+    cmd(db, &["take", "WORKERDATA2"])?.assert().code(2);
+    cmd(db, &["reap"])?.assert().success().stdout("1\n");
+    cmd(db, &["take", "--track-pid", "WORKERDATA2"])?
+        .assert()
+        .success();
+    // a worker that is still running isn't reaped
+    cmd(db, &["reap"])?.assert().success().stdout("");
+    Ok(())
+}
+
+#[test]
+fn test_capture() -> Result<()> {
+    let db_file = NamedTempFile::new()?;
+    let db = db_file.path();
+    cmd(db, &["init"])?.assert().success();
+    cmd(db, &["create", "-c", "2", "-d", "JOBDATA"])?
+        .assert()
+        .success();
+    // output is passed through and logged
+    cmd(db, &["take", "WORKERDATA1"])?.assert().success();
+    cmd(
+        db,
+        &[
+            "monitor",
+            "--capture",
+            "both",
+            "--capture-limit",
+            "8",
+            "WORKERDATA1",
+            "--",
+            "sh",
+            "-c",
+            "echo $0-out; sleep 0.1; echo $0-err >&2",
+            "captured",
+        ],
+    )?
+    .assert()
+    .success()
+    .stdout("captured-out\n")
+    .stderr("captured-err\n");
+    let status = cmd(db, &["list-taken", "-v"])?.output()?.stdout;
+    let status = String::from_utf8(status)?;
+    assert!(status.contains("red-err"));
+    assert!(!status.contains("captured-err"));
+    // log-finish can log data too
+    cmd(db, &["take", "WORKERDATA1"])?.assert().success();
+    cmd(db, &["log-start", "WORKERDATA1"])?.assert().success();
+    cmd(db, &["log-finish", "--read-data", "WORKERDATA1", "0"])?
+        .write_stdin("RESULTDATA")
+        .assert()
+        .success();
+    let status = cmd(db, &["list-taken", "-v"])?.output()?.stdout;
+    assert!(String::from_utf8(status)?.contains("RESULTDATA"));
+    Ok(())
+}
+
+#[test]
+fn test_stats() -> Result<()> {
+    let db_file = NamedTempFile::new()?;
+    let db = db_file.path();
+    cmd(db, &["init"])?.assert().success();
+    cmd(db, &["create", "-c", "3", "-d", "JOBDATA"])?
+        .assert()
+        .success();
+    cmd(db, &["stats"])?
+        .assert()
+        .success()
+        .stdout(format!("1\t3\t0\t0\t0\t0{}\n", "\t".repeat(12)));
+    cmd(db, &["take", "WORKERDATA1"])?.assert().success();
+    cmd(db, &["monitor", "WORKERDATA1", "--", "false"])?
+        .assert()
+        .failure();
+    cmd(db, &["take", "WORKERDATA1"])?.assert().success();
+    let stats = cmd(db, &["stats"])?.output()?.stdout;
+    let stats = String::from_utf8(stats)?;
+    let fields: Vec<_> = stats.trim_end_matches('\n').split('\t').collect();
+    assert_eq!(fields.len(), 18);
+    assert_eq!(&fields[..6], &["1", "1", "1", "0", "0", "1"]);
+    assert!(fields[6..].iter().all(|x| x.parse::<f64>().is_ok()));
+    // the monitored command used some memory
+    assert!(fields[15].parse::<f64>()? > 0.0);
+    cmd(db, &["stats", "-v"])?.assert().success();
+    let status = cmd(db, &["list-taken", "-v"])?.output()?.stdout;
+    assert!(String::from_utf8(status)?.contains("MiB"));
+    Ok(())
+}
+
+#[test]
+fn test_eta() -> Result<()> {
+    let db_file = NamedTempFile::new()?;
+    let db = db_file.path();
+    cmd(db, &["init"])?.assert().success();
+    let eta = cmd(db, &["eta"])?.output()?.stdout;
+    assert!(String::from_utf8(eta)?.starts_with("all\t0\t"));
+    cmd(db, &["create", "-c", "2", "-d", "JOBDATA"])?
+        .assert()
+        .success();
+    // no history to estimate from
+    cmd(db, &["eta"])?
+        .assert()
+        .success()
+        .stdout("1\t2\t\nall\t2\t\n");
+    cmd(db, &["take", "WORKERDATA1"])?.assert().success();
+    cmd(db, &["monitor", "WORKERDATA1", "--", "true"])?
+        .assert()
+        .success();
+    cmd(db, &["take", "WORKERDATA1"])?.assert().success();
+    cmd(db, &["log-start", "WORKERDATA1"])?.assert().success();
+    let eta = cmd(db, &["eta"])?.output()?.stdout;
+    let eta = String::from_utf8(eta)?;
+    let lines: Vec<Vec<_>> = eta.lines().map(|l| l.split('\t').collect()).collect();
+    assert_eq!(lines.len(), 2);
+    assert_eq!(&lines[0][..2], &["1", "1"]);
+    assert_eq!(&lines[1][..2], &["all", "1"]);
+    assert!(lines[1][2].parse::<f64>().is_ok());
+    cmd(db, &["eta", "-v"])?.assert().success();
+    Ok(())
+}
+
+#[test]
+fn test_after() -> Result<()> {
+    let db_file = NamedTempFile::new()?;
+    let db = db_file.path();
+    cmd(db, &["init"])?.assert().success();
+    cmd(db, &["create", "-c", "1", "-d", "FIRST"])?
+        .assert()
+        .success();
+    cmd(db, &["create", "-c", "1", "-d", "SECOND", "--after", "1"])?
+        .assert()
+        .success()
+        .stdout("2\n");
+    cmd(db, &["list-available"])?
+        .assert()
+        .success()
+        .stdout("1\n");
+    let list = cmd(db, &["list-available", "-v"])?.output()?.stdout;
+    assert!(String::from_utf8(list)?.contains("waiting for 1"));
+    cmd(db, &["take", "WORKERDATA1"])?
+        .assert()
+        .success()
+        .stdout("FIRST");
+    // not available until the dependency has finished
+    cmd(db, &["take", "WORKERDATA2"])?.assert().code(2);
+    cmd(db, &["monitor", "WORKERDATA1", "--", "true"])?
+        .assert()
+        .success();
+    cmd(db, &["take", "WORKERDATA2"])?
+        .assert()
+        .success()
+        .stdout("SECOND");
+    Ok(())
+}
+
+#[test]
+fn test_after_notok() -> Result<()> {
+    let db_file = NamedTempFile::new()?;
+    let db = db_file.path();
+    cmd(db, &["init"])?.assert().success();
+    cmd(db, &["create", "-c", "1", "-d", "WORK"])?
+        .assert()
+        .success();
+    cmd(
+        db,
+        &["create", "-c", "1", "-d", "ALERT", "--after-notok", "1"],
+    )?
+    .assert()
+    .success();
+    cmd(
+        db,
+        &["create", "-c", "1", "-d", "CLEANUP", "--after-any", "1"],
+    )?
+    .assert()
+    .success();
+    cmd(db, &["take", "WORKERDATA1"])?
+        .assert()
+        .success()
+        .stdout("WORK");
+    cmd(db, &["take", "WORKERDATA2"])?.assert().code(2);
+    // a command that can't be started counts as a failure
+    cmd(
+        db,
+        &["monitor", "WORKERDATA1", "--", "nosuchcommand_foobarbaz"],
+    )?
+    .assert()
+    .failure();
+    cmd(db, &["list-available"])?
+        .assert()
+        .success()
+        .stdout("2\n3\n");
+    Ok(())
+}
+
+#[test]
+fn test_run() -> Result<()> {
+    let db_file = NamedTempFile::new()?;
+    let db = db_file.path();
+    cmd(db, &["init"])?.assert().success();
+    cmd(db, &["create", "-c", "3", "-d", "ONE"])?
+        .assert()
+        .success();
+    cmd(db, &["create", "-c", "2", "-d", "TWO", "--after", "1"])?
+        .assert()
+        .success();
+    let output = cmd(db, &["run", "-j", "2", "--", "sh", "-c", "echo ran-$0"])?.output()?;
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout)?;
+    assert_eq!(stdout.matches("ran-ONE").count(), 3);
+    assert_eq!(stdout.matches("ran-TWO").count(), 2);
+    cmd(db, &["list-available"])?.assert().success().stdout("");
+    // a failed job is reported in the exit status
+    cmd(db, &["create", "-c", "1", "-d", "THREE"])?
+        .assert()
+        .success();
+    cmd(db, &["run", "--", "false"])?.assert().code(1);
+    // a pool needs at least one slot
+    let output = cmd(db, &["run", "-j", "0", "--", "true"])?.output()?;
+    assert!(!output.status.success());
+    assert!(String::from_utf8(output.stderr)?.contains("positive integer"));
+    Ok(())
+}
+
+#[test]
+fn test_run_interrupt() -> Result<()> {
+    let db_file = NamedTempFile::new()?;
+    let db = db_file.path();
+    cmd(db, &["init"])?.assert().success();
+    cmd(db, &["create", "-c", "2", "-d", "JOBDATA"])?
+        .assert()
+        .success();
+    let mut run = std::process::Command::new(assert_cmd::cargo::cargo_bin("jerbs"))
+        .arg(db)
+        .args(["run", "--", "sh", "-c", "sleep 1"])
+        .spawn()?;
+    std::thread::sleep(std::time::Duration::from_millis(300));
+    // the running job is allowed to finish, but no more are taken
+    std::process::Command::new("kill")
+        .args(["-INT", &run.id().to_string()])
+        .status()?;
+    assert_eq!(run.wait()?.code(), Some(1));
+    let stats = String::from_utf8(cmd(db, &["stats"])?.output()?.stdout)?;
+    let fields: Vec<_> = stats.split('\t').collect();
+    assert_eq!(&fields[..6], &["1", "1", "0", "0", "1", "0"]);
+    Ok(())
+}
+
+#[test]
+fn test_placeholders() -> Result<()> {
+    let db_file = NamedTempFile::new()?;
+    let db = db_file.path();
+    cmd(db, &["init"])?.assert().success();
+    cmd(db, &["create", "-c", "2", "-d", "JOBDATA"])?
+        .assert()
+        .success();
+    cmd(db, &["take", "WORKERDATA1"])?.assert().success();
+    cmd(
+        db,
+        &[
+            "monitor",
+            "WORKERDATA1",
+            "--",
+            "echo",
+            "{data}.{task}.{rep}:{worker}",
+        ],
+    )?
+    .assert()
+    .success()
+    .stdout("JOBDATA.1.0:WORKERDATA1\n");
+    // with placeholders, run doesn't append the data
+    cmd(db, &["run", "--worker-id", "W", "--", "echo", "{job}"])?
+        .assert()
+        .success()
+        .stdout("2\n");
+    Ok(())
+}
+
+#[test]
+fn test_monitor_env() -> Result<()> {
+    let db_file = NamedTempFile::new()?;
+    let db = db_file.path();
+    cmd(db, &["init"])?.assert().success();
+    cmd(db, &["create", "-c", "1", "-d", "JOBDATA"])?
+        .assert()
+        .success();
+    cmd(db, &["take", "WORKERDATA1"])?.assert().success();
+    // JERBS_DB works from another directory, even if the database was given as a relative path
+    let script = "echo $JERBS_TASK_ID $JERBS_JOB_ID $JERBS_REPETITION $JERBS_WORKER; \
+                  cd /; \"$0\" \"$JERBS_DB\" get-data $JERBS_TASK_ID";
+    let jerbs = assert_cmd::cargo::cargo_bin("jerbs");
+    let jerbs = jerbs.to_str().unwrap();
+    let (dir, file) = (db.parent().unwrap(), Path::new(db.file_name().unwrap()));
+    cmd(
+        file,
+        &["monitor", "WORKERDATA1", "--", "sh", "-c", script, jerbs],
+    )?
+    .current_dir(dir)
+    .assert()
+    .success()
+    .stdout("1 1 0 WORKERDATA1\nJOBDATA");
+    Ok(())
+}
+
+#[test]
+fn test_stdin_data() -> Result<()> {
+    let db_file = NamedTempFile::new()?;
+    let db = db_file.path();
+    cmd(db, &["init"])?.assert().success();
+    cmd(db, &["create", "-c", "2"])?
+        .write_stdin("it's \"shell\"\n$hostile\0")
+        .assert()
+        .success();
+    cmd(db, &["take", "WORKERDATA1"])?.assert().success();
+    cmd(db, &["monitor", "--stdin-data", "WORKERDATA1", "--", "cat"])?
+        .assert()
+        .success()
+        .stdout("it's \"shell\"\n$hostile\0");
+    // a command that doesn't read its input is fine too
+    cmd(db, &["run", "--stdin-data", "--", "true"])?
+        .assert()
+        .success()
+        .stdout("");
+    Ok(())
+}
+
+#[test]
+fn test_monitor_take() -> Result<()> {
+    let db_file = NamedTempFile::new()?;
+    let db = db_file.path();
+    cmd(db, &["init"])?.assert().success();
+    cmd(db, &["monitor", "--take", "WORKERDATA1", "--", "true"])?
+        .assert()
+        .code(2);
+    cmd(db, &["create", "-c", "1", "-d", "JOBDATA"])?
+        .assert()
+        .success();
+    // the job is already running by the time the command starts
+    let jerbs = assert_cmd::cargo::cargo_bin("jerbs");
+    cmd(
+        db,
+        &[
+            "monitor",
+            "--take",
+            "WORKERDATA1",
+            "--",
+            "sh",
+            "-c",
+            "\"$0\" \"$JERBS_DB\" list-running; echo $1",
+            jerbs.to_str().unwrap(),
+            "{data}",
+        ],
+    )?
+    .assert()
+    .success()
+    .stdout("1\nJOBDATA\n");
+    cmd(
+        db,
+        &[
+            "monitor",
+            "--take",
+            "--wait",
+            "--wait-timeout",
+            "0.1",
+            "WORKERDATA1",
+            "--",
+            "true",
+        ],
+    )?
+    .assert()
+    .code(2);
+    Ok(())
+}
+
+#[test]
+fn test_monitor_timeout() -> Result<()> {
+    let db_file = NamedTempFile::new()?;
+    let db = db_file.path();
+    cmd(db, &["init"])?.assert().success();
+    cmd(db, &["create", "-c", "2", "-d", "JOBDATA"])?
+        .assert()
+        .success();
+    // a command that ignores SIGTERM is killed
+    let start = std::time::Instant::now();
+    cmd(
+        db,
+        &[
+            "monitor",
+            "--take",
+            "--timeout",
+            "0.2s",
+            "--kill-after",
+            "0.2",
+            "WORKERDATA1",
+            "--",
+            "sh",
+            "-c",
+            "trap '' TERM; sleep 10",
+        ],
+    )?
+    .assert()
+    .code(124);
+    assert!(start.elapsed() < std::time::Duration::from_secs(5));
+    let status = cmd(db, &["list-taken", "-v"])?.output()?.stdout;
+    assert!(String::from_utf8(status)?.contains(" 515 "));
+    // a command that finishes in time is unaffected
+    cmd(
+        db,
+        &[
+            "monitor",
+            "--take",
+            "--timeout",
+            "1m",
+            "WORKERDATA1",
+            "--",
+            "true",
+        ],
+    )?
+    .assert()
+    .success();
+    Ok(())
+}
+
+#[test]
+fn test_limits() -> Result<()> {
+    let db_file = NamedTempFile::new()?;
+    let db = db_file.path();
+    cmd(db, &["init"])?.assert().success();
+    cmd(
+        db,
+        &[
+            "create",
+            "-c",
+            "2",
+            "-d",
+            "JOBDATA",
+            "--max-memory",
+            "100M",
+            "--nice",
+            "5",
+        ],
+    )?
+    .assert()
+    .success();
+    cmd(db, &["modify", "1", "--max-cpu-time", "7"])?
+        .assert()
+        .success();
+    let script = "ulimit -v; ulimit -t; nice";
+    cmd(db, &["monitor", "--take", "W", "--", "sh", "-c", script])?
+        .assert()
+        .success()
+        .stdout("102400\n7\n5\n");
+    // the monitor's own settings take precedence
+    cmd(
+        db,
+        &[
+            "monitor", "--take", "--nice", "6", "W", "--", "sh", "-c", script,
+        ],
+    )?
+    .assert()
+    .success()
+    .stdout("102400\n7\n6\n");
+    // limits can be removed
+    cmd(
+        db,
+        &["modify", "1", "-a", "1", "--clear-limits", "--nice", "3"],
+    )?
+    .assert()
+    .success();
+    cmd(db, &["monitor", "--take", "W", "--", "sh", "-c", script])?
+        .assert()
+        .success()
+        .stdout("unlimited\nunlimited\n3\n");
+    Ok(())
+}
+
+#[test]
+fn test_monitor_signals() -> Result<()> {
+    let db_file = NamedTempFile::new()?;
+    let db = db_file.path();
+    cmd(db, &["init"])?.assert().success();
+    cmd(db, &["create", "-c", "2", "-d", "JOBDATA"])?
+        .assert()
+        .success();
+    let monitor = |script: &str| -> Result<std::process::ExitStatus> {
+        let mut monitor = std::process::Command::new(assert_cmd::cargo::cargo_bin("jerbs"))
+            .arg(db)
+            .args(["monitor", "--take", "WORKERDATA1", "--", "sh", "-c", script])
+            .spawn()?;
+        std::thread::sleep(std::time::Duration::from_millis(300));
+        std::process::Command::new("kill")
+            .args(["-TERM", &monitor.id().to_string()])
+            .status()?;
+        Ok(monitor.wait()?)
+    };
+    // the signal is passed on, and the command's fate is logged
+    assert_eq!(monitor("sleep 5")?.code(), Some(128 + 15));
+    // a command can handle it
+    assert_eq!(
+        monitor("trap 'exit 3' TERM; sleep 5 & wait")?.code(),
+        Some(3)
+    );
+    let status = cmd(db, &["list-taken", "-v"])?.output()?.stdout;
+    let status = String::from_utf8(status)?;
+    assert!(status.contains(" 271 "));
+    assert!(status.contains(" 3 "));
+    Ok(())
+}
+
+#[test]
+fn test_monitor_signal_waiting() -> Result<()> {
+    let db_file = NamedTempFile::new()?;
+    let db = db_file.path();
+    cmd(db, &["init"])?.assert().success();
+    let mut monitor = std::process::Command::new(assert_cmd::cargo::cargo_bin("jerbs"))
+        .arg(db)
+        .args(["monitor", "--take", "--wait", "WORKERDATA1", "--", "true"])
+        .spawn()?;
+    std::thread::sleep(std::time::Duration::from_millis(300));
+    std::process::Command::new("kill")
+        .args(["-INT", &monitor.id().to_string()])
+        .status()?;
+    // with no command to pass the signal on to, it ends the wait
+    assert_eq!(monitor.wait()?.code(), Some(128 + 2));
+    cmd(db, &["create", "-c", "1", "-d", "JOBDATA"])?
+        .assert()
+        .success();
+    let available = cmd(db, &["list-available"])?.output()?.stdout;
+    assert_eq!(String::from_utf8(available)?.trim(), "1");
+    Ok(())
+}
+
+#[test]
+fn test_kill() -> Result<()> {
+    let db_file = NamedTempFile::new()?;
+    let db = db_file.path();
+    cmd(db, &["init"])?.assert().success();
+    cmd(db, &["create", "-c", "1", "-d", "JOBDATA"])?
+        .assert()
+        .success();
+    cmd(db, &["kill", "1"])?.assert().failure();
+    let mut monitor = std::process::Command::new(assert_cmd::cargo::cargo_bin("jerbs"))
+        .arg(db)
+        .args([
+            "monitor",
+            "--take",
+            "-r",
+            "WORKERDATA1",
+            "--",
+            "sleep",
+            "10",
+        ])
+        .spawn()?;
+    std::thread::sleep(std::time::Duration::from_millis(300));
+    let start = std::time::Instant::now();
+    cmd(db, &["kill", "--signal", "INT", "1"])?
+        .assert()
+        .success();
+    assert_eq!(monitor.wait()?.code(), Some(128 + 2));
+    assert!(start.elapsed() < std::time::Duration::from_secs(5));
+    let status = cmd(db, &["list-taken", "-v"])?.output()?.stdout;
+    assert!(String::from_utf8(status)?.contains(" 516 "));
+    // it isn't requeued
+    cmd(db, &["take", "WORKERDATA2"])?.assert().code(2);
+    Ok(())
+}
+
+#[test]
+fn test_suspend() -> Result<()> {
+    let db_file = NamedTempFile::new()?;
+    let db = db_file.path();
+    cmd(db, &["init"])?.assert().success();
+    cmd(db, &["create", "-c", "1", "-d", "JOBDATA"])?
+        .assert()
+        .success();
+    cmd(db, &["suspend", "1"])?.assert().failure();
+    let dir = tempfile::tempdir()?;
+    let out = dir.path().join("out");
+    let script = format!("sleep 1.5; echo done > {}", out.display());
+    let mut monitor = std::process::Command::new(assert_cmd::cargo::cargo_bin("jerbs"))
+        .arg(db)
+        .args(["monitor", "--take", "WORKERDATA", "--", "sh", "-c", &script])
+        .spawn()?;
+    std::thread::sleep(std::time::Duration::from_millis(200));
+    cmd(db, &["suspend", "1"])?.assert().success();
+    std::thread::sleep(std::time::Duration::from_millis(2500));
+    // stopped before it could finish
+    assert!(!out.exists());
+    cmd(db, &["resume", "1"])?.assert().success();
+    assert_eq!(monitor.wait()?.code(), Some(0));
+    assert!(out.exists());
+    Ok(())
+}
+
+#[test]
+fn test_preempt() -> Result<()> {
+    let db_file = NamedTempFile::new()?;
+    let db = db_file.path();
+    cmd(db, &["init"])?.assert().success();
+    cmd(
+        db,
+        &[
+            "create",
+            "-c",
+            "1",
+            "-p",
+            "10",
+            "--preemptible",
+            "-d",
+            "BACKGROUND",
+        ],
+    )?
+    .assert()
+    .success();
+    let mut monitor = std::process::Command::new(assert_cmd::cargo::cargo_bin("jerbs"))
+        .arg(db)
+        .args(["monitor", "--take", "WORKERDATA1", "--", "sleep", "10"])
+        .spawn()?;
+    std::thread::sleep(std::time::Duration::from_millis(300));
+    cmd(
+        db,
+        &["create", "-c", "1", "-p", "0", "--preempt", "-d", "URGENT"],
+    )?
+    .assert()
+    .success();
+    assert_eq!(monitor.wait()?.code(), Some(128 + 15));
+    let status = cmd(db, &["list-taken", "-v"])?.output()?.stdout;
+    assert!(String::from_utf8(status)?.contains(" 517 "));
+    let urgent = cmd(db, &["take", "WORKERDATA2"])?.output()?.stdout;
+    assert_eq!(urgent, b"URGENT");
+    let background = cmd(db, &["take", "WORKERDATA3"])?.output()?.stdout;
+    assert_eq!(background, b"BACKGROUND");
+
+    // nothing is preempted if a waiting worker takes the job
+    cmd(
+        db,
+        &[
+            "create",
+            "-c",
+            "1",
+            "-p",
+            "10",
+            "--preemptible",
+            "-d",
+            "BACKGROUND",
+        ],
+    )?
+    .assert()
+    .success();
+    let mut background = std::process::Command::new(assert_cmd::cargo::cargo_bin("jerbs"))
+        .arg(db)
+        .args(["monitor", "--take", "WORKERDATA4", "--", "sleep", "10"])
+        .spawn()?;
+    std::thread::sleep(std::time::Duration::from_millis(300));
+    let mut monitor = std::process::Command::new(assert_cmd::cargo::cargo_bin("jerbs"))
+        .arg(db)
+        .args(["monitor", "--take", "--wait", "WORKERDATA5", "--", "true"])
+        .spawn()?;
+    std::thread::sleep(std::time::Duration::from_millis(300));
+    cmd(
+        db,
+        &["create", "-c", "1", "-p", "0", "--preempt", "-d", "URGENT"],
+    )?
+    .assert()
+    .success()
+    .stderr("");
+    assert_eq!(monitor.wait()?.code(), Some(0));
+    background.kill()?;
+    background.wait()?;
+    Ok(())
+}
+
+#[test]
+fn test_job_ids() -> Result<()> {
+    let db_file = NamedTempFile::new()?;
+    let db = db_file.path();
+    cmd(db, &["init"])?.assert().success();
+    cmd(db, &["create", "-c", "2", "-d", "JOBDATA"])?
+        .assert()
+        .success();
+    let first = cmd(db, &["take", "--print-job-id", "WORKER"])?
+        .output()?
+        .stdout;
+    assert_eq!(first, b"1\nJOBDATA");
+    let second = cmd(db, &["take", "--print-job-id", "WORKER"])?
+        .output()?
+        .stdout;
+    assert_eq!(second, b"2\nJOBDATA");
+    // the worker's older job can be logged by id
+    cmd(db, &["log-start", "--job", "1", "WORKER"])?
+        .assert()
+        .success();
+    cmd(db, &["list-running"])?.assert().success().stdout("1\n");
+    cmd(db, &["monitor", "--job", "2", "WORKER", "--", "true"])?
+        .assert()
+        .success();
+    cmd(db, &["log-finish", "--job", "1", "WORKER", "3"])?
+        .assert()
+        .success();
+    cmd(db, &["log-finish", "--job", "1", "OTHER", "0"])?
+        .assert()
+        .failure();
+    let status = String::from_utf8(cmd(db, &["list-taken", "-v"])?.output()?.stdout)?;
+    let results: Vec<_> = status
+        .lines()
+        .filter(|line| line.contains("WORKER"))
+        .map(|line| line.split('│').nth(5).unwrap().trim())
+        .collect();
+    assert_eq!(results, ["3", "0"]);
+    Ok(())
+}
+
+#[test]
+fn test_take_format() -> Result<()> {
+    let db_file = NamedTempFile::new()?;
+    let db = db_file.path();
+    cmd(db, &["init"])?.assert().success();
+    cmd(db, &["create", "-c", "4", "-d", "JOB DATA"])?
+        .assert()
+        .success();
+    let out = cmd(db, &["take", "--format", "raw", "W"])?.output()?.stdout;
+    assert_eq!(out, b"JOB DATA");
+    let out = cmd(db, &["take", "--format", "nul", "W"])?.output()?.stdout;
+    assert_eq!(out, b"2\x001\x00JOB DATA");
+    let out = cmd(db, &["take", "--format", "json", "W"])?
+        .output()?
+        .stdout;
+    assert_eq!(
+        String::from_utf8(out)?,
+        "{\"data\":\"Sk9CIERBVEE=\",\"job\":3,\"rep\":2,\"task\":1,\"worker\":\"W\"}\n"
+    );
+    let out = cmd(db, &["take", "--template", "{job}:{rep}:{data}", "W"])?
+        .output()?
+        .stdout;
+    assert_eq!(out, b"4:3:JOB DATA");
+    cmd(db, &["take", "--format", "json", "W"])?
+        .assert()
+        .code(2);
+    Ok(())
+}
+
+#[test]
+fn test_suspend_timeout() -> Result<()> {
+    let db_file = NamedTempFile::new()?;
+    let db = db_file.path();
+    cmd(db, &["init"])?.assert().success();
+    cmd(
+        db,
+        &["create", "-c", "1", "--timeout", "2", "-d", "JOBDATA"],
+    )?
+    .assert()
+    .success();
+    let mut monitor = std::process::Command::new(assert_cmd::cargo::cargo_bin("jerbs"))
+        .arg(db)
+        .args(["monitor", "--take", "WORKERDATA", "--", "sleep", "1"])
+        .spawn()?;
+    std::thread::sleep(std::time::Duration::from_millis(200));
+    cmd(db, &["suspend", "1"])?.assert().success();
+    // suspended for longer than the timeout
+    std::thread::sleep(std::time::Duration::from_millis(3000));
+    cmd(db, &["resume", "1"])?.assert().success();
+    assert_eq!(monitor.wait()?.code(), Some(0));
+    Ok(())
+}
+
+#[test]
+fn test_duration_suffixes() -> Result<()> {
+    let db_file = NamedTempFile::new()?;
+    let db = db_file.path();
+    cmd(db, &["init"])?.assert().success();
+    cmd(db, &["take", "--wait", "--timeout", "0.1s", "W"])?
+        .assert()
+        .code(2);
+    cmd(
+        db,
+        &[
+            "create",
+            "-c",
+            "1",
+            "--max-attempts",
+            "2",
+            "--retry-delay",
+            "1m",
+            "-d",
+            "X",
+        ],
+    )?
+    .assert()
+    .success();
+    cmd(db, &["take", "--lease", "5m", "W"])?.assert().success();
+    Ok(())
+}